@@ -1,13 +1,15 @@
 use crate::{MetadataApi, PixelApi};
 use anyhow::{Context, Result};
+use flusher::{FlusherStatus, FlusherStatusTracker};
 use metadata_storage::PostgresMetadataStorage;
 use pixel_storage::PixelStorageTrait;
 use poem::{
     get, handler,
-    http::Method,
+    http::{Method, StatusCode},
     listener::TcpListener,
     middleware::{Cors, Tracing},
-    EndpointExt, Route, Server,
+    web::{Data, Json},
+    EndpointExt, IntoResponse, Route, Server,
 };
 use serde::{Deserialize, Serialize};
 use std::{
@@ -44,12 +46,17 @@ pub async fn start_api(config: ApiConfig, route: Route) -> Result<()> {
 }
 
 /// This convenience method helps with building an API with the desired routes based
-/// on the APIs the user has built.
+/// on the APIs the user has built. The status of the given flushers is reported by
+/// the health endpoint at /v1/health.
 pub fn build_full_route(
     pixel_storage: Option<Arc<dyn PixelStorageTrait>>,
     metadata_storage: Option<Arc<PostgresMetadataStorage>>,
+    flusher_status_trackers: Vec<Arc<FlusherStatusTracker>>,
 ) -> Result<Route> {
-    let mut route = Route::new().nest("/", get(v1_root));
+    let mut route = Route::new().nest("/", get(v1_root)).at(
+        "/health",
+        get(health).data(Arc::new(flusher_status_trackers)),
+    );
     if let Some(pixel_storage) = pixel_storage {
         let pixel_api = PixelApi::new(pixel_storage);
         let pixel_route = pixel_api.get_route()?;
@@ -73,3 +80,27 @@ async fn root() -> String {
 async fn v1_root() -> String {
     "Hello from the root at /v1!!".to_string()
 }
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    flushers: Vec<FlusherStatus>,
+}
+
+/// Report the status of every flusher. This returns 503 if any of them is
+/// unhealthy, i.e. its last flush failed or it gave up after failing too many times
+/// in a row, so the service is not publishing images.
+#[handler]
+async fn health(
+    flusher_status_trackers: Data<&Arc<Vec<Arc<FlusherStatusTracker>>>>,
+) -> impl IntoResponse {
+    let flushers: Vec<_> = flusher_status_trackers
+        .iter()
+        .map(|tracker| tracker.status())
+        .collect();
+    let status = if flushers.iter().all(FlusherStatus::is_healthy) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Json(HealthResponse { flushers }).with_status(status)
+}
@@ -1,28 +1,169 @@
-use anyhow::{Context, Result};
+mod stream;
+mod tiles;
+
+use self::{
+    stream::stream_changes,
+    tiles::{tile_region, TileInfo},
+};
+use anyhow::{anyhow, bail, Context, Result};
 use aptos_move_graphql_scalars::Address;
-use pixel_storage::PixelStorageTrait;
+use pixel_storage::{CanvasChanges, ImageFormat, PixelStorageError, PixelStorageTrait, Scale};
 use poem::{
     get, handler,
-    web::{Data, Path},
-    Endpoint, EndpointExt, Response, Route,
+    http::{header, HeaderMap, StatusCode},
+    web::{Data, Json, Path, Query},
+    Endpoint, EndpointExt, Error, Response, Route,
 };
+use serde::{Deserialize, Serialize};
 use std::{str::FromStr, sync::Arc};
 
 pub const BASE: &str = "/pixels";
 
+/// Query params for getting a scaled image of a canvas, e.g. `?scale=4` to make each
+/// canvas pixel 4x4 or `?width=256` for a 256 pixel wide thumbnail. At most one of
+/// these can be set.
+#[derive(Debug, Deserialize)]
+struct ScaleParams {
+    scale: Option<u32>,
+    width: Option<u32>,
+}
+
+/// Work out which format the client wants an image in. An extension on the last
+/// path segment (e.g. `.webp`) takes priority, otherwise we use the Accept header,
+/// otherwise PNG. This returns the path segment without the extension.
+fn negotiate_format<'a>(segment: &'a str, headers: &HeaderMap) -> Result<(&'a str, ImageFormat)> {
+    if let Some((name, extension)) = segment.rsplit_once('.') {
+        let format = ImageFormat::from_extension(extension)
+            .ok_or_else(|| anyhow!("Unsupported image format {}", extension))?;
+        return Ok((name, format));
+    }
+
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or_default();
+    Ok((segment, format_from_accept(accept)))
+}
+
+/// Pick the format the client most prefers from an Accept header. Anything we don't
+/// recognize, including wildcards, means PNG.
+fn format_from_accept(accept: &str) -> ImageFormat {
+    let mut candidates = accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let format = ImageFormat::from_content_type(parts.next()?)?;
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((format, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect::<Vec<_>>();
+    // This is a stable sort so for equal quality the order in the header wins.
+    candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+    candidates
+        .first()
+        .map(|(format, _)| *format)
+        .unwrap_or_default()
+}
+
+/// Turn an error from pixel storage into a response, using 404 if the canvas doesn't
+/// exist and 400 if the requested image is too large, rather than the usual 500.
+fn storage_error(error: anyhow::Error) -> Error {
+    let status = error
+        .chain()
+        .find_map(|cause| match cause.downcast_ref::<PixelStorageError>() {
+            Some(PixelStorageError::CanvasNotFound { .. }) => Some(StatusCode::NOT_FOUND),
+            Some(PixelStorageError::ScaledImageTooLarge { .. }) => Some(StatusCode::BAD_REQUEST),
+            _ => None,
+        });
+    match status {
+        Some(status) => Error::from_string(format!("{:#}", error), status),
+        None => Error::from(error),
+    }
+}
+
+/// Query params for getting the changes to a canvas.
+#[derive(Debug, Deserialize)]
+struct ChangesParams {
+    since_version: u64,
+}
+
+/// The pixels that changed in a canvas, or a request to fetch the full image. Either
+/// way, `last_version` is what to pass as `since_version` next time.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum ChangesResponse {
+    /// Each change is `[index, color, version]`, with only the latest change for
+    /// each pixel, ordered by version.
+    Changes {
+        last_version: u64,
+        changes: Vec<(u32, u8, u64)>,
+    },
+    FullImageRequired {
+        last_version: u64,
+    },
+}
+
+impl From<CanvasChanges> for ChangesResponse {
+    fn from(changes: CanvasChanges) -> Self {
+        match changes {
+            CanvasChanges::Changes {
+                last_version,
+                changes,
+            } => ChangesResponse::Changes {
+                last_version,
+                changes: changes
+                    .into_iter()
+                    .map(|change| (change.index, change.color, change.version))
+                    .collect(),
+            },
+            CanvasChanges::FullImageRequired { last_version } => {
+                ChangesResponse::FullImageRequired { last_version }
+            },
+        }
+    }
+}
+
+/// Check we can scale the image this way in the format the client wants, raw
+/// images for example can't be scaled down.
+fn check_format_supports_scale(format: ImageFormat, scale: Scale) -> Result<()> {
+    if !format.supports_scale(scale) {
+        bail!(
+            "Images in {} format can't be scaled down",
+            format.extension()
+        );
+    }
+    Ok(())
+}
+
+fn image_response(data: Vec<u8>, format: ImageFormat) -> Response {
+    Response::builder()
+        .header(header::VARY, "Accept")
+        .body(data)
+        .set_content_type(format.content_type())
+}
+
+/// Get a canvas as an image. The format can be picked with an extension, e.g.
+/// `/<address>.webp`, or with the Accept header.
 #[handler]
 async fn get_image(
     pixel_storage: Data<&Arc<dyn PixelStorageTrait>>,
     Path(address): Path<String>,
+    Query(params): Query<ScaleParams>,
+    headers: &HeaderMap,
 ) -> poem::Result<Response> {
-    let address = if address.ends_with(".png") {
-        address[..address.len() - 4].to_string()
-    } else {
-        address
-    };
-    let address = Address::from_str(&address).context("Invalid address")?;
-    let png_data = pixel_storage
-        .get_canvas_as_png(&address)
+    let scale = Scale::from_options(params.scale, params.width)
+        .map_err(|e| Error::from_string(format!("{:#}", e), StatusCode::BAD_REQUEST))?;
+    let (address, format) = negotiate_format(&address, headers)
+        .map_err(|e| Error::from_string(format!("{:#}", e), StatusCode::BAD_REQUEST))?;
+    check_format_supports_scale(format, scale)
+        .map_err(|e| Error::from_string(format!("{:#}", e), StatusCode::NOT_ACCEPTABLE))?;
+    let address = Address::from_str(address).context("Invalid address")?;
+    let image_data = pixel_storage
+        .get_scaled_canvas_as_image(&address, scale, format)
         .await
         .with_context(|| {
             format!(
@@ -30,10 +171,75 @@ async fn get_image(
                 address.to_canonical_string()
             )
         })
-        .unwrap();
-    Ok(Response::builder()
-        .body(png_data)
-        .set_content_type("image/png"))
+        .map_err(storage_error)?;
+    Ok(image_response(image_data, format))
+}
+
+/// Describe the tiles available for a canvas.
+#[handler]
+async fn get_tile_info(
+    pixel_storage: Data<&Arc<dyn PixelStorageTrait>>,
+    Path(address): Path<String>,
+) -> poem::Result<Json<TileInfo>> {
+    let address = Address::from_str(&address).context("Invalid address")?;
+    let info = pixel_storage
+        .get_canvas_info(&address)
+        .await
+        .with_context(|| format!("Failed to get info for canvas {}", address))
+        .map_err(storage_error)?;
+    Ok(Json(TileInfo::new(&info)))
+}
+
+/// Get a single tile of a canvas as an image. See `tiles.rs` for how tiles are laid
+/// out. The format is picked the same way as for whole canvases.
+#[handler]
+async fn get_tile(
+    pixel_storage: Data<&Arc<dyn PixelStorageTrait>>,
+    Path((address, z, x, y)): Path<(String, u32, u32, String)>,
+    headers: &HeaderMap,
+) -> poem::Result<Response> {
+    let address = Address::from_str(&address).context("Invalid address")?;
+    let (y, format) = negotiate_format(&y, headers)
+        .map_err(|e| Error::from_string(format!("{:#}", e), StatusCode::BAD_REQUEST))?;
+    let y = u32::from_str(y)
+        .map_err(|_| Error::from_string("Invalid tile y", StatusCode::BAD_REQUEST))?;
+    let info = pixel_storage
+        .get_canvas_info(&address)
+        .await
+        .with_context(|| format!("Failed to get info for canvas {}", address))
+        .map_err(storage_error)?;
+    let (region, scale) =
+        tile_region(&info, z, x, y).ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+    check_format_supports_scale(format, scale)
+        .map_err(|e| Error::from_string(format!("{:#}", e), StatusCode::NOT_ACCEPTABLE))?;
+    let image_data = pixel_storage
+        .get_canvas_region_as_image(&address, region, scale, format)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to get tile {}/{}/{} for canvas {}",
+                z, x, y, address
+            )
+        })
+        .map_err(storage_error)?;
+    Ok(image_response(image_data, format))
+}
+
+/// Get the pixels that changed after a version, e.g. `?since_version=1234`, so the
+/// client can apply them to an image it already has.
+#[handler]
+async fn get_changes(
+    pixel_storage: Data<&Arc<dyn PixelStorageTrait>>,
+    Path(address): Path<String>,
+    Query(params): Query<ChangesParams>,
+) -> poem::Result<Json<ChangesResponse>> {
+    let address = Address::from_str(&address).context("Invalid address")?;
+    let changes = pixel_storage
+        .get_canvas_changes(&address, params.since_version)
+        .await
+        .with_context(|| format!("Failed to get changes for canvas {}", address))
+        .map_err(storage_error)?;
+    Ok(Json(changes.into()))
 }
 
 #[handler]
@@ -54,6 +260,10 @@ impl PixelApi {
         Ok(Route::new()
             .at("/", get(root))
             .at("/:address", get(get_image))
+            .at("/:address/changes", get(get_changes))
+            .at("/:address/stream", get(stream_changes))
+            .at("/:address/tiles", get(get_tile_info))
+            .at("/:address/tiles/:z/:x/:y", get(get_tile))
             .data(self.pixel_storage.clone()))
     }
 }
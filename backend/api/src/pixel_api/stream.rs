@@ -0,0 +1,141 @@
+// Clients can watch a canvas change as it happens rather than polling for the image.
+// Each message is the same JSON as the changes endpoint returns, so clients handle
+// both the same way. If the client passes `since_version`, the first message is the
+// changes since then, otherwise it only gets changes from when it connected.
+//
+// We serve this over a WebSocket if the client asks to upgrade, otherwise as
+// Server-Sent Events. Updates come from a broadcast channel, see `changes.rs` in
+// pixel storage. If a client reads too slowly it misses updates rather than holding
+// anything up, in which case we send `full_image_required` and keep going.
+
+use super::{storage_error, ChangesResponse};
+use anyhow::Context;
+use aptos_move_graphql_scalars::Address;
+use futures::{stream, SinkExt, Stream, StreamExt};
+use pixel_storage::{CanvasChanges, CanvasUpdate, PixelStorageTrait};
+use poem::{
+    handler,
+    web::{
+        sse::{Event, SSE},
+        websocket::{Message, WebSocket},
+        Data, Path, Query,
+    },
+    IntoResponse, Response,
+};
+use serde::Deserialize;
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::warn;
+
+/// How often we send something on an idle SSE stream, so proxies don't close it.
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Query params for streaming the changes to a canvas.
+#[derive(Debug, Deserialize)]
+pub(super) struct StreamParams {
+    since_version: Option<u64>,
+}
+
+/// Stream the changes to a canvas as they're applied, over a WebSocket or SSE.
+#[handler]
+pub(super) async fn stream_changes(
+    pixel_storage: Data<&Arc<dyn PixelStorageTrait>>,
+    Path(address): Path<String>,
+    Query(params): Query<StreamParams>,
+    websocket: Option<WebSocket>,
+) -> poem::Result<Response> {
+    let address = Address::from_str(&address).context("Invalid address")?;
+    // We subscribe before looking at the canvas so we don't miss anything applied
+    // in between. Anything we get twice is skipped by version.
+    let receiver = pixel_storage.subscribe_to_updates();
+    pixel_storage
+        .get_canvas_info(&address)
+        .await
+        .with_context(|| format!("Failed to get info for canvas {}", address))
+        .map_err(storage_error)?;
+    let (initial, last_version) = match params.since_version {
+        Some(since_version) => {
+            let changes = pixel_storage
+                .get_canvas_changes(&address, since_version)
+                .await
+                .with_context(|| format!("Failed to get changes for canvas {}", address))
+                .map_err(storage_error)?;
+            let last_version = changes.last_version();
+            (Some(ChangesResponse::from(changes)), last_version)
+        },
+        None => (None, 0),
+    };
+    let messages = stream::iter(initial)
+        .chain(canvas_updates(receiver, address, last_version))
+        .map(|response| serde_json::to_string(&response).unwrap_or_default());
+
+    let response = match websocket {
+        Some(websocket) => websocket
+            .on_upgrade(move |socket| async move {
+                let (mut sink, mut incoming) = socket.split();
+                let mut messages = Box::pin(messages);
+                loop {
+                    tokio::select! {
+                        // We don't expect anything from the client, we just need to
+                        // notice when it goes away.
+                        message = incoming.next() => match message {
+                            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                            Some(Ok(_)) => continue,
+                        },
+                        message = messages.next() => match message {
+                            Some(message) => {
+                                if sink.send(Message::Text(message)).await.is_err() {
+                                    break;
+                                }
+                            },
+                            None => break,
+                        },
+                    }
+                }
+            })
+            .into_response(),
+        None => SSE::new(messages.map(Event::message))
+            .keep_alive(SSE_KEEP_ALIVE)
+            .into_response(),
+    };
+    Ok(response)
+}
+
+/// Turn the updates to every canvas into the changes to the given canvas after the
+/// given version.
+fn canvas_updates(
+    receiver: Receiver<Arc<CanvasUpdate>>,
+    canvas_address: Address,
+    last_version: u64,
+) -> impl Stream<Item = ChangesResponse> {
+    stream::unfold(
+        (receiver, last_version),
+        move |(mut receiver, mut last_version)| async move {
+            loop {
+                let changes = match receiver.recv().await {
+                    Ok(update) if update.canvas_address != canvas_address => continue,
+                    Ok(update) => update.changes.clone(),
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(
+                            "Stream for canvas {} missed {} updates, sending full image required",
+                            canvas_address, missed
+                        );
+                        CanvasChanges::FullImageRequired { last_version }
+                    },
+                    Err(RecvError::Closed) => return None,
+                };
+                if let CanvasChanges::Changes {
+                    last_version: version,
+                    ..
+                } = &changes
+                {
+                    if *version <= last_version {
+                        continue;
+                    }
+                }
+                last_version = last_version.max(changes.last_version());
+                return Some((changes.into(), (receiver, last_version)));
+            }
+        },
+    )
+}
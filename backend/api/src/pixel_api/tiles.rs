@@ -0,0 +1,80 @@
+// Tiles follow the XYZ scheme used by web maps. At zoom 0 the whole canvas fits in
+// a single tile, and each zoom level after that doubles the number of tiles along
+// each side. At the native zoom level one canvas pixel is one tile pixel; beyond
+// that tiles are upscaled, below that they're downscaled. Tiles are numbered from
+// the top left. Tiles on the right and bottom edges are cut off where the canvas
+// ends rather than padded out to the full tile size.
+
+use pixel_storage::{CanvasInfo, Region, Scale};
+use serde::{Deserialize, Serialize};
+
+/// The width and height of a tile in pixels.
+pub const TILE_SIZE: u32 = 256;
+
+/// How many zoom levels past the native zoom level we serve.
+pub const MAX_UPSCALE_ZOOM_LEVELS: u32 = 5;
+
+/// What a client needs to know to request tiles for a canvas.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TileInfo {
+    pub width: u32,
+    pub height: u32,
+    pub tile_size: u32,
+    /// The zoom level at which one canvas pixel is one tile pixel.
+    pub native_zoom: u32,
+    pub max_zoom: u32,
+}
+
+impl TileInfo {
+    pub fn new(info: &CanvasInfo) -> Self {
+        let native_zoom = native_zoom(info);
+        Self {
+            width: info.width,
+            height: info.height,
+            tile_size: TILE_SIZE,
+            native_zoom,
+            max_zoom: native_zoom + MAX_UPSCALE_ZOOM_LEVELS,
+        }
+    }
+}
+
+/// The lowest zoom level at which the canvas fits in a single tile at 1:1.
+fn native_zoom(info: &CanvasInfo) -> u32 {
+    let longest_side = info.width.max(info.height) as u64;
+    let mut zoom = 0;
+    while (TILE_SIZE as u64) << zoom < longest_side {
+        zoom += 1;
+    }
+    zoom
+}
+
+/// Work out which part of the canvas a tile covers and how to scale it. Returns None
+/// if the tile is outside the canvas or the zoom level is too high.
+pub fn tile_region(info: &CanvasInfo, z: u32, x: u32, y: u32) -> Option<(Region, Scale)> {
+    let native_zoom = native_zoom(info);
+    let (canvas_pixels_per_tile, scale) = if z <= native_zoom {
+        let factor = 1u32.checked_shl(native_zoom - z)?;
+        (TILE_SIZE as u64 * factor as u64, Scale::Down(factor))
+    } else {
+        let levels = z - native_zoom;
+        if levels > MAX_UPSCALE_ZOOM_LEVELS {
+            return None;
+        }
+        let factor = 1 << levels;
+        ((TILE_SIZE / factor) as u64, Scale::Up(factor))
+    };
+
+    let (width, height) = (info.width as u64, info.height as u64);
+    let left = x as u64 * canvas_pixels_per_tile;
+    let top = y as u64 * canvas_pixels_per_tile;
+    if left >= width || top >= height {
+        return None;
+    }
+    let region = Region {
+        x: left as u32,
+        y: top as u32,
+        width: canvas_pixels_per_tile.min(width - left) as u32,
+        height: canvas_pixels_per_tile.min(height - top) as u32,
+    };
+    Some((region, scale))
+}
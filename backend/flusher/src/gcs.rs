@@ -1,38 +1,146 @@
-use super::FlusherTrait;
+use super::{FlusherState, FlusherTrait, RenditionConfig, RetryConfig};
 use anyhow::{Context, Result};
-use aptos_move_graphql_scalars::Address;
 use async_trait::async_trait;
 use google_cloud_storage::{
-    client::{Client, ClientConfig},
-    http::objects::{
-        upload::{UploadObjectRequest, UploadType},
-        Object,
+    client::{google_cloud_auth::credentials::CredentialsFile, Client, ClientConfig},
+    http::{
+        objects::{
+            download::Range,
+            get::GetObjectRequest,
+            upload::{UploadObjectRequest, UploadType},
+            Object,
+        },
+        Error as GcsError,
     },
 };
-use pixel_storage::PixelStorageTrait;
+use google_cloud_token::{TokenSource, TokenSourceProvider};
+use pixel_storage::{ImageFormat, PixelStorageTrait};
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct GcsFlusherConfig {
     bucket_name: String,
+    /// How to authenticate with GCS.
+    #[serde(default)]
+    auth: GcsAuthConfig,
+    /// Where to send requests, e.g. `http://localhost:4443` for a local
+    /// fake-gcs-server. If not set we use GCS.
+    #[serde(default)]
+    endpoint_url: Option<String>,
+    /// Where the bucket is publicly served from, for the URLs in the manifest, e.g.
+    /// a CDN in front of it. If not set we use the URL of the bucket.
+    #[serde(default)]
+    public_url: Option<String>,
     #[serde(default = "GcsFlusherConfig::default_flush_interval")]
     flush_interval: Duration,
+    /// Scaled copies of each image to upload alongside the full image.
+    #[serde(default)]
+    renditions: Vec<RenditionConfig>,
+    /// The formats to publish each image in. Raw images are only published for
+    /// renditions that scale up, since raw images can't be scaled down.
+    #[serde(default = "GcsFlusherConfig::default_image_formats")]
+    image_formats: Vec<ImageFormat>,
+    /// How to retry after failing to flush.
+    #[serde(default)]
+    retry: RetryConfig,
 }
 
 impl GcsFlusherConfig {
     pub fn default_flush_interval() -> Duration {
         Duration::from_millis(1200)
     }
+
+    pub fn default_image_formats() -> Vec<ImageFormat> {
+        vec![ImageFormat::Png]
+    }
+
+    fn public_url(&self) -> String {
+        match &self.public_url {
+            Some(public_url) => public_url.trim_end_matches('/').to_string(),
+            None => format!(
+                "{}/{}",
+                self.endpoint_url
+                    .as_deref()
+                    .unwrap_or("https://storage.googleapis.com")
+                    .trim_end_matches('/'),
+                self.bucket_name
+            ),
+        }
+    }
+}
+
+/// How the GcsFlusher authenticates with GCS, picked with `type`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GcsAuthConfig {
+    /// Use the credentials of the environment, e.g. the service account of the GCP
+    /// instance we're running on or `GOOGLE_APPLICATION_CREDENTIALS`.
+    #[default]
+    Ambient,
+    /// Use the given service account key file.
+    KeyFile { path: PathBuf },
+    /// Don't authenticate at all. This is only useful with an emulator, e.g. a
+    /// local fake-gcs-server.
+    Anonymous,
+}
+
+impl GcsAuthConfig {
+    async fn client_config(&self) -> Result<ClientConfig> {
+        Ok(match self {
+            GcsAuthConfig::Ambient => ClientConfig::default()
+                .with_auth()
+                .await
+                .context("Failed to create GCP GCS client config")?,
+            GcsAuthConfig::KeyFile { path } => {
+                let credentials =
+                    CredentialsFile::new_from_file(path.to_string_lossy().into_owned())
+                        .await
+                        .with_context(|| {
+                            format!("Failed to read GCP key file {}", path.display())
+                        })?;
+                ClientConfig::default()
+                    .with_credentials(credentials)
+                    .await
+                    .context("Failed to create GCP GCS client config from key file")?
+            },
+            GcsAuthConfig::Anonymous => ClientConfig {
+                token_source_provider: Box::new(AnonymousTokenSourceProvider),
+                ..Default::default()
+            },
+        })
+    }
 }
 
-/// This assumes that we're running inside GCP. If we're not then this won't work
-/// because we use ClientConfig::default().with_auth() to create the client.
+/// The client needs a token for every request, so when we don't authenticate we
+/// give it a placeholder one. Emulators don't check it.
+#[derive(Debug)]
+struct AnonymousTokenSourceProvider;
+
+impl TokenSourceProvider for AnonymousTokenSourceProvider {
+    fn token_source(&self) -> Arc<dyn TokenSource> {
+        Arc::new(AnonymousTokenSource)
+    }
+}
+
+#[derive(Debug)]
+struct AnonymousTokenSource;
+
+#[async_trait]
+impl TokenSource for AnonymousTokenSource {
+    async fn token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok("Bearer anonymous".to_string())
+    }
+}
+
+/// Uploads images to a GCS bucket. By default this uses the credentials of the
+/// environment, which generally means we're running inside GCP, see `GcsAuthConfig`
+/// for the alternatives.
 #[derive(Clone)]
 pub struct GcsFlusher {
     config: GcsFlusherConfig,
-    pixel_storage: Arc<dyn PixelStorageTrait>,
     client: Client,
+    state: Arc<FlusherState>,
 }
 
 impl GcsFlusher {
@@ -40,34 +148,57 @@ impl GcsFlusher {
         config: GcsFlusherConfig,
         pixel_storage: Arc<dyn PixelStorageTrait>,
     ) -> Result<Self> {
-        let client_config = ClientConfig::default()
-            .with_auth()
-            .await
-            .context("Failed to create GCP GCS client config")?;
+        let state = FlusherState::new(
+            "gcs",
+            pixel_storage,
+            config.flush_interval,
+            &config.renditions,
+            &config.image_formats,
+            &config.retry,
+        )?;
+        let mut client_config = config.auth.client_config().await?;
+        if let Some(endpoint_url) = &config.endpoint_url {
+            client_config.storage_endpoint = endpoint_url.clone();
+        }
         let client = Client::new(client_config);
         Ok(Self {
             config,
-            pixel_storage,
             client,
+            state: Arc::new(state),
         })
     }
 
-    pub async fn write_image_to_gcs(
+    fn get_object_request(&self, name: &str) -> GetObjectRequest {
+        GetObjectRequest {
+            bucket: self.config.bucket_name.clone(),
+            object: name.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl FlusherTrait for GcsFlusher {
+    fn state(&self) -> &FlusherState {
+        &self.state
+    }
+
+    async fn put_object(
         &self,
-        canvas_address: Address,
-        png_data: Vec<u8>,
+        name: &str,
+        content_type: &str,
+        data: Vec<u8>,
+        metadata: HashMap<String, String>,
     ) -> Result<()> {
-        let extension = "png";
-
-        let filename = format!("images/{}.{}", canvas_address, extension);
         // We can't use uploadType::Simple because it doesn't allow us to set the cache
         // control parameters.
         let upload_type = UploadType::Multipart(Box::new(Object {
-            name: filename.clone(),
-            content_type: format!("image/{}", extension).into(),
-            size: png_data.len() as i64,
+            name: name.to_string(),
+            content_type: content_type.to_string().into(),
+            size: data.len() as i64,
             // Don't let the content be cached anywhere.
             cache_control: Some("no-cache, no-store, max-age=0".to_string()),
+            metadata: (!metadata.is_empty()).then_some(metadata),
             ..Default::default()
         }));
         self.client
@@ -76,28 +207,35 @@ impl GcsFlusher {
                     bucket: self.config.bucket_name.clone(),
                     ..Default::default()
                 },
-                png_data.clone(),
+                data,
                 &upload_type,
             )
             .await
-            .with_context(|| format!("Failed to image for address {} to GCS", canvas_address))?;
-
+            .context("Failed to upload to GCS")?;
         Ok(())
     }
-}
 
-#[async_trait]
-impl FlusherTrait for GcsFlusher {
-    fn get_interval(&self) -> Duration {
-        self.config.flush_interval
+    async fn head_object(&self, name: &str) -> Result<Option<HashMap<String, String>>> {
+        match self.client.get_object(&self.get_object_request(name)).await {
+            Ok(object) => Ok(Some(object.metadata.unwrap_or_default())),
+            Err(GcsError::Response(e)) if e.code == 404 => Ok(None),
+            Err(e) => Err(e).context("Failed to get object metadata from GCS"),
+        }
     }
 
-    async fn flush(&self) -> Result<()> {
-        let pngs = self.pixel_storage.get_canvases_as_pngs().await?;
-        for (canvas_address, png_data) in pngs {
-            // This could be optimized by only writing images that have changed.
-            self.write_image_to_gcs(canvas_address, png_data).await?;
+    async fn get_object(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match self
+            .client
+            .download_object(&self.get_object_request(name), &Range::default())
+            .await
+        {
+            Ok(data) => Ok(Some(data)),
+            Err(GcsError::Response(e)) if e.code == 404 => Ok(None),
+            Err(e) => Err(e).context("Failed to download object from GCS"),
         }
-        Ok(())
+    }
+
+    fn object_url(&self, name: &str) -> String {
+        format!("{}/{}", self.config.public_url(), name)
     }
 }
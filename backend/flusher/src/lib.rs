@@ -1,52 +1,536 @@
 mod gcs;
 mod local;
+mod manifest;
+mod s3;
+mod status;
+mod tracker;
 
-use anyhow::Result;
-pub use gcs::{GcsFlusher, GcsFlusherConfig};
+use anyhow::{bail, Context, Result};
+use aptos_move_graphql_scalars::Address;
+pub use gcs::{GcsAuthConfig, GcsFlusher, GcsFlusherConfig};
 pub use local::{LocalFlusher, LocalFlusherConfig};
-use std::time::Duration;
+pub use manifest::{Manifest, ManifestCanvas, MANIFEST_NAME};
+use pixel_storage::{CanvasInfo, ImageFormat, PixelStorageTrait, Scale};
+pub use s3::{S3Credentials, S3Flusher, S3FlusherConfig};
+use serde::{Deserialize, Serialize};
+pub use status::{FlusherStatus, FlusherStatusTracker, RetryConfig};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::task::JoinHandle;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+pub use tracker::{hash_content, UploadTracker};
+
+/// An extra copy of each canvas image, scaled, for a flusher to publish next to the
+/// full image. For example a thumbnail for social embeds.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RenditionConfig {
+    /// This is appended to the name of the image, e.g. `<address>_thumbnail.png`.
+    pub name: String,
+
+    /// Scale up by this factor, so each canvas pixel becomes a square this many
+    /// pixels wide.
+    #[serde(default)]
+    pub scale: Option<u32>,
+
+    /// Resize to this width, keeping the aspect ratio.
+    #[serde(default)]
+    pub width: Option<u32>,
+}
+
+/// A validated rendition, built from a `RenditionConfig`.
+#[derive(Clone, Debug)]
+pub struct Rendition {
+    pub name: String,
+    pub scale: Scale,
+}
+
+impl Rendition {
+    pub fn from_configs(configs: &[RenditionConfig]) -> Result<Vec<Self>> {
+        let mut names = HashSet::new();
+        let mut renditions = Vec::new();
+        for config in configs {
+            if config.name.is_empty()
+                || !config
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                bail!(
+                    "Rendition name {:?} must be non-empty and only contain letters, numbers, _ and -",
+                    config.name
+                );
+            }
+            if config.scale == Some(0) || config.width == Some(0) {
+                bail!(
+                    "Rendition {} must have a scale or width of at least 1",
+                    config.name
+                );
+            }
+            if !names.insert(config.name.clone()) {
+                bail!("There are multiple renditions named {}", config.name);
+            }
+            renditions.push(Self {
+                name: config.name.clone(),
+                scale: Scale::from_options(config.scale, config.width)?,
+            });
+        }
+        Ok(renditions)
+    }
+}
+
+/// Check the image formats a flusher is configured to publish.
+pub fn validate_image_formats(image_formats: &[ImageFormat]) -> Result<()> {
+    if image_formats.is_empty() {
+        bail!("At least one image format must be configured");
+    }
+    let unique: HashSet<_> = image_formats.iter().collect();
+    if unique.len() != image_formats.len() {
+        bail!("The same image format is configured multiple times");
+    }
+    Ok(())
+}
+
+/// Raw images can only be scaled up, so we skip renditions that would need them
+/// scaled down.
+pub fn rendition_supports_format(rendition: &Rendition, format: ImageFormat) -> bool {
+    format.supports_scale(rendition.scale)
+}
+
+/// The name (without extension) we give the image of a canvas, or of a rendition of
+/// it if a rendition name is given.
+pub fn image_name(canvas_address: &Address, rendition_name: Option<&str>) -> String {
+    match rendition_name {
+        Some(rendition_name) => format!("{}_{}", canvas_address, rendition_name),
+        None => canvas_address.to_string(),
+    }
+}
+
+/// The current time in seconds since the unix epoch.
+pub fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+/// The custom metadata key we keep the hash of the canvas under, see `flush_canvas`.
+const CONTENT_HASH_METADATA_KEY: &str = "graffio-content-hash";
+
+/// What every flusher keeps, whatever it flushes to.
+pub struct FlusherState {
+    /// The storage to read canvases from.
+    pub pixel_storage: Arc<dyn PixelStorageTrait>,
+    pub flush_interval: Duration,
+    /// The renditions this flusher publishes.
+    pub renditions: Vec<Rendition>,
+    /// The formats this flusher publishes each image in.
+    pub image_formats: Vec<ImageFormat>,
+    /// The renditions and formats as configured, serialized, which we hash along
+    /// with each canvas so changing them uploads every canvas again.
+    pub published_config: Vec<u8>,
+    /// How to retry after failing to flush, see `status.rs`.
+    pub retry: RetryConfig,
+    /// What this flusher has uploaded since startup, see `tracker.rs`.
+    pub upload_tracker: UploadTracker,
+    /// The status of this flusher, for reporting from the health endpoint.
+    pub status_tracker: Arc<FlusherStatusTracker>,
+}
+
+impl FlusherState {
+    /// Check the renditions and image formats from the config of a flusher. The name
+    /// is what the flusher is called in the health endpoint.
+    pub fn new(
+        name: &str,
+        pixel_storage: Arc<dyn PixelStorageTrait>,
+        flush_interval: Duration,
+        renditions: &[RenditionConfig],
+        image_formats: &[ImageFormat],
+        retry: &RetryConfig,
+    ) -> Result<Self> {
+        let published_config = serde_json::to_vec(&(renditions, image_formats))
+            .context("Failed to serialize renditions and image formats")?;
+        let renditions = Rendition::from_configs(renditions).context("Invalid renditions")?;
+        validate_image_formats(image_formats).context("Invalid image formats")?;
+        let updates = pixel_storage.subscribe_to_updates();
+        Ok(Self {
+            pixel_storage,
+            flush_interval,
+            renditions,
+            image_formats: image_formats.to_vec(),
+            published_config,
+            retry: retry.clone(),
+            upload_tracker: UploadTracker::new(updates),
+            status_tracker: Arc::new(FlusherStatusTracker::new(name, retry)),
+        })
+    }
+}
 
 /// A flusher is something that can periodically flush local data to remote storage.
+/// Each flusher only needs to know how to read and write objects where it flushes
+/// to, everything else is shared.
 #[async_trait::async_trait]
 pub trait FlusherTrait: Send + Sync + 'static {
-    /// Flush the data just once.
-    async fn flush(&self) -> Result<()>;
+    fn state(&self) -> &FlusherState;
+
+    /// Write an object, replacing it if it exists. Names are relative to where the
+    /// flusher publishes to, e.g. `manifest.json`. The metadata is kept alongside the
+    /// object, for flushers that can.
+    async fn put_object(
+        &self,
+        name: &str,
+        content_type: &str,
+        data: Vec<u8>,
+        metadata: HashMap<String, String>,
+    ) -> Result<()>;
 
-    /// Get the interval at which we should flush.
-    fn get_interval(&self) -> Duration;
+    /// Get the metadata of an object without downloading it, or None if there is no
+    /// such object.
+    async fn head_object(&self, name: &str) -> Result<Option<HashMap<String, String>>>;
+
+    /// Get the contents of an object, or None if there is no such object.
+    async fn get_object(&self, name: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Where the object can be fetched from once published, for the manifest.
+    fn object_url(&self, name: &str) -> String;
+
+    /// The name of the object we publish an image as, given the name from
+    /// `image_name`.
+    fn image_object_name(&self, image_name: &str, format: ImageFormat) -> String {
+        format!("images/{}.{}", image_name, format.extension())
+    }
+
+    /// The status of this flusher, for reporting from the health endpoint.
+    fn status_tracker(&self) -> &Arc<FlusherStatusTracker> {
+        &self.state().status_tracker
+    }
+
+    /// Upload every image of the canvas. The full image in the first configured
+    /// format is uploaded last and is the only one that gets the content hash, so if
+    /// it has the hash, everything else for the canvas was uploaded before it. See
+    /// `get_uploaded_hash`.
+    async fn flush_canvas(
+        &self,
+        canvas_address: Address,
+        png_data: Vec<u8>,
+        content_hash: &str,
+    ) -> Result<()> {
+        let state = self.state();
+        let mut images = Vec::new();
+        for rendition in &state.renditions {
+            for format in &state.image_formats {
+                if rendition_supports_format(rendition, *format) {
+                    images.push((Some(rendition), *format));
+                }
+            }
+        }
+        images.extend(
+            state
+                .image_formats
+                .iter()
+                .rev()
+                .map(|format| (None, *format)),
+        );
+
+        let num_images = images.len();
+        for (i, (rendition, format)) in images.into_iter().enumerate() {
+            let image_data = match (rendition, format) {
+                (None, ImageFormat::Png) => png_data.clone(),
+                _ => {
+                    let scale = rendition.map_or(Scale::Up(1), |rendition| rendition.scale);
+                    state
+                        .pixel_storage
+                        .get_scaled_canvas_as_image(&canvas_address, scale, format)
+                        .await?
+                },
+            };
+            let mut metadata = HashMap::new();
+            if i == num_images - 1 {
+                metadata.insert(
+                    CONTENT_HASH_METADATA_KEY.to_string(),
+                    content_hash.to_string(),
+                );
+            }
+            let image_name = image_name(&canvas_address, rendition.map(|r| r.name.as_str()));
+            let name = self.image_object_name(&image_name, format);
+            self.put_object(&name, format.content_type(), image_data, metadata)
+                .await
+                .with_context(|| format!("Failed to write {}", name))?;
+        }
+        Ok(())
+    }
+
+    /// Get the hash of the canvas as it was last uploaded, e.g. before a restart, if
+    /// the flusher keeps it. If this returns None we upload the canvas.
+    async fn get_uploaded_hash(&self, canvas_address: &Address) -> Result<Option<String>> {
+        let image_name = image_name(canvas_address, None);
+        let name = self.image_object_name(&image_name, self.state().image_formats[0]);
+        Ok(self
+            .head_object(&name)
+            .await
+            .with_context(|| format!("Failed to get metadata of {}", name))?
+            .and_then(|mut metadata| metadata.remove(CONTENT_HASH_METADATA_KEY)))
+    }
+
+    /// Where the image with the given name (see `image_name`) can be fetched from
+    /// once published, for the manifest.
+    fn image_url(&self, image_name: &str, format: ImageFormat) -> String {
+        self.object_url(&self.image_object_name(image_name, format))
+    }
+
+    /// Publish the manifest, replacing the one that was there. See `manifest.rs`.
+    async fn publish_manifest(&self, manifest_json: Vec<u8>) -> Result<()> {
+        self.put_object(
+            MANIFEST_NAME,
+            "application/json",
+            manifest_json,
+            HashMap::new(),
+        )
+        .await
+        .context("Failed to write manifest")
+    }
+
+    /// Get the manifest that was last published, e.g. before a restart.
+    async fn get_published_manifest(&self) -> Result<Option<Manifest>> {
+        match self
+            .get_object(MANIFEST_NAME)
+            .await
+            .context("Failed to read manifest")?
+        {
+            Some(data) => Ok(Some(
+                serde_json::from_slice(&data).context("Failed to parse manifest")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Flush the data just once. This only uploads canvases that changed since this
+    /// flusher last uploaded them, then publishes the manifest if it changed. See
+    /// `tracker.rs` for how we tell which canvases changed.
+    ///
+    /// The manifest covers every canvas in storage. When we go through every canvas,
+    /// storage only gives us the images of the canvases it has open, e.g. those
+    /// touched since startup, so for the rest we keep what we published if it is
+    /// still up to date. Otherwise, e.g. for a canvas we've never published, we get
+    /// its image.
+    async fn flush(&self) -> Result<()> {
+        let state = self.state();
+        let tracker = &state.upload_tracker;
+        let changed = tracker.changed_canvases().await;
+        // We get the info first so that the version in the manifest is never newer
+        // than the image. If the canvas changes in between, the manifest gets the
+        // newer version next time.
+        let (infos, mut pngs, published_manifest) = match &changed {
+            Some(changed) if changed.is_empty() => {
+                debug!("No canvases changed since the last flush");
+                return Ok(());
+            },
+            Some(changed) => {
+                let mut infos = Vec::with_capacity(changed.len());
+                for canvas_address in changed {
+                    infos.push(
+                        state
+                            .pixel_storage
+                            .get_canvas_info(canvas_address)
+                            .await
+                            .with_context(|| {
+                                format!("Failed to get info for canvas {}", canvas_address)
+                            })?,
+                    );
+                }
+                (infos, HashMap::new(), tracker.published_manifest().await)
+            },
+            None => {
+                let infos = state.pixel_storage.list_canvases().await?;
+                let pngs = state.pixel_storage.get_canvases_as_pngs().await?;
+                let published_manifest = match tracker.published_manifest().await {
+                    Some(manifest) => Some(manifest),
+                    None => self
+                        .get_published_manifest()
+                        .await
+                        .context("Failed to get published manifest")?,
+                };
+                (infos, pngs, published_manifest)
+            },
+        };
+        let published: HashMap<Address, ManifestCanvas> = published_manifest
+            .clone()
+            .unwrap_or_default()
+            .canvases
+            .into_iter()
+            .map(|canvas| (canvas.canvas_address, canvas))
+            .collect();
+        // Canvases that didn't change stay in the manifest as they are.
+        let mut manifest = Manifest::default();
+        if let Some(changed) = &changed {
+            manifest.canvases.extend(
+                published
+                    .values()
+                    .filter(|canvas| !changed.contains(&canvas.canvas_address))
+                    .cloned(),
+            );
+        }
+        let mut num_flushed = 0;
+        for info in &infos {
+            let canvas_address = info.canvas_address;
+            let png_data = match pngs.remove(&canvas_address) {
+                Some(png_data) => png_data,
+                None => {
+                    // A canvas in the changed set has changed even if its info
+                    // hasn't, e.g. if it was repaired.
+                    let published_canvas =
+                        published.get(&canvas_address).filter(|_| changed.is_none());
+                    if let Some(canvas) = published_canvas {
+                        let unchanged = self.manifest_canvas(
+                            info,
+                            canvas.content_hash.clone(),
+                            canvas.updated_at_secs,
+                        );
+                        if unchanged == *canvas {
+                            manifest.canvases.push(unchanged);
+                            continue;
+                        }
+                    }
+                    state
+                        .pixel_storage
+                        .get_canvas_as_png(&canvas_address)
+                        .await
+                        .with_context(|| {
+                            format!("Failed to get canvas {} as a png", canvas_address)
+                        })?
+                },
+            };
+            let content_hash = hash_content(&png_data, &state.published_config);
+            let uploaded_hash = match tracker.uploaded_hash(&canvas_address).await {
+                Some(uploaded_hash) => Some(uploaded_hash),
+                None => self
+                    .get_uploaded_hash(&canvas_address)
+                    .await
+                    .with_context(|| {
+                        format!("Failed to get uploaded hash for canvas {}", canvas_address)
+                    })?,
+            };
+            if uploaded_hash.as_deref() != Some(content_hash.as_str()) {
+                self.flush_canvas(canvas_address, png_data, &content_hash)
+                    .await
+                    .with_context(|| format!("Failed to flush canvas {}", canvas_address))?;
+                num_flushed += 1;
+            }
+            tracker
+                .record_upload(canvas_address, content_hash.clone())
+                .await;
+
+            let updated_at_secs = match published.get(&canvas_address) {
+                Some(canvas) if canvas.content_hash == content_hash => canvas.updated_at_secs,
+                _ => unix_time_secs(),
+            };
+            manifest
+                .canvases
+                .push(self.manifest_canvas(info, content_hash, updated_at_secs));
+        }
+        debug!("Flushed {} changed canvases", num_flushed);
+
+        manifest
+            .canvases
+            .sort_by_key(|canvas| canvas.canvas_address.to_string());
+        if published_manifest.as_ref() != Some(&manifest) {
+            let manifest_json =
+                serde_json::to_vec_pretty(&manifest).context("Failed to serialize manifest")?;
+            self.publish_manifest(manifest_json)
+                .await
+                .context("Failed to publish manifest")?;
+            debug!("Published manifest of {} canvases", manifest.canvases.len());
+        }
+        tracker.record_manifest(manifest).await;
+        tracker.record_flushed(changed.as_ref()).await;
+        Ok(())
+    }
+
+    /// Describe a canvas we published, for the manifest.
+    fn manifest_canvas(
+        &self,
+        info: &CanvasInfo,
+        content_hash: String,
+        updated_at_secs: u64,
+    ) -> ManifestCanvas {
+        let urls = |rendition: Option<&Rendition>| {
+            let name = image_name(&info.canvas_address, rendition.map(|r| r.name.as_str()));
+            self.state()
+                .image_formats
+                .iter()
+                .filter(|format| {
+                    rendition.map_or(true, |rendition| {
+                        rendition_supports_format(rendition, **format)
+                    })
+                })
+                .map(|format| {
+                    (
+                        format.extension().to_string(),
+                        self.image_url(&name, *format),
+                    )
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        ManifestCanvas {
+            canvas_address: info.canvas_address,
+            width: info.width,
+            height: info.height,
+            last_applied_version: info.last_applied_version,
+            updated_at_secs,
+            content_hash,
+            images: urls(None),
+            renditions: self
+                .state()
+                .renditions
+                .iter()
+                .map(|rendition| (rendition.name.clone(), urls(Some(rendition))))
+                .collect(),
+        }
+    }
 
     /// Consume the flusher to create a task in which we periodically flush the data.
-    /// This returns a handle for the task. This task should never end.
+    /// This returns a handle for the task. The task only ends if flushing fails more
+    /// times in a row than the retry config allows.
     fn run(self) -> JoinHandle<()>
     where
         Self: Sized,
     {
         tokio::spawn(async move {
-            let mut num_consecutive_failures = 0;
+            let retry_config = self.state().retry.clone();
+            let status_tracker = self.status_tracker().clone();
             loop {
                 debug!("Flushing data");
-                match self.flush().await {
+                let wait = match self.flush().await {
                     Ok(_) => {
                         info!("Flushed data succesfully");
+                        status_tracker.record_success();
+                        self.state().flush_interval
                     },
                     Err(e) => {
                         error!("Failed to flush data: {:?}", e);
-                        num_consecutive_failures += 1;
-                        if num_consecutive_failures >= 5 {
+                        let num_consecutive_failures = status_tracker.record_failure(&e);
+                        if num_consecutive_failures >= retry_config.max_consecutive_failures {
                             error!(
-                                "Failed to flush data too many times ({}), bailing out!",
+                                "Failed to flush data too many times in a row ({}), bailing out!",
                                 num_consecutive_failures
                             );
                             return;
                         }
+                        let backoff = retry_config.backoff(num_consecutive_failures);
+                        warn!(
+                            "Failed to flush data {} times in a row, retrying in {:?}",
+                            num_consecutive_failures, backoff
+                        );
+                        backoff
                     },
-                }
-                tokio::time::sleep(self.get_interval()).await;
+                };
+                tokio::time::sleep(wait).await;
             }
         })
-        // This ^ task is never meant to end. If it does, we should move past the
-        // select_all we use on the tasks and ultimately shut down.
+        // This ^ task is only meant to end if flushing keeps failing. If it does, we
+        // move past the select_all we use on the tasks and ultimately shut down.
     }
 }
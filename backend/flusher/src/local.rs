@@ -1,30 +1,47 @@
-use super::FlusherTrait;
+use super::{FlusherState, FlusherTrait, RenditionConfig, RetryConfig};
 use anyhow::{Context, Result};
-use aptos_move_graphql_scalars::Address;
 use async_trait::async_trait;
-use pixel_storage::PixelStorageTrait;
+use pixel_storage::{ImageFormat, PixelStorageTrait};
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct LocalFlusherConfig {
     flush_dir: PathBuf,
     #[serde(default = "LocalFlusherConfig::default_flush_interval")]
     flush_interval: Duration,
+    /// Scaled copies of each image to write alongside the full image.
+    #[serde(default)]
+    renditions: Vec<RenditionConfig>,
+    /// The formats to publish each image in. Raw images are only published for
+    /// renditions that scale up, since raw images can't be scaled down.
+    #[serde(default = "LocalFlusherConfig::default_image_formats")]
+    image_formats: Vec<ImageFormat>,
+    /// How to retry after failing to flush.
+    #[serde(default)]
+    retry: RetryConfig,
 }
 
 impl LocalFlusherConfig {
     pub fn default_flush_interval() -> Duration {
         Duration::from_millis(3000)
     }
+
+    pub fn default_image_formats() -> Vec<ImageFormat> {
+        vec![ImageFormat::Png]
+    }
 }
 
 /// This is only intended for use in testing, it is not required in any of the main
 /// deployment configurations, including for any approach related to serving images.
+///
+/// Images are written straight into the flush directory, without the `images/`
+/// prefix the other flushers use. We don't keep the hash of what we wrote, so every
+/// canvas is written again after a restart. Writing to local disk is cheap anyway.
 #[derive(Clone)]
 pub struct LocalFlusher {
     config: LocalFlusherConfig,
-    pixel_storage: Arc<dyn PixelStorageTrait>,
+    state: Arc<FlusherState>,
 }
 
 impl LocalFlusher {
@@ -32,36 +49,68 @@ impl LocalFlusher {
         config: LocalFlusherConfig,
         pixel_storage: Arc<dyn PixelStorageTrait>,
     ) -> Result<Self> {
+        let state = FlusherState::new(
+            "local",
+            pixel_storage,
+            config.flush_interval,
+            &config.renditions,
+            &config.image_formats,
+            &config.retry,
+        )?;
         Ok(Self {
             config,
-            pixel_storage,
+            state: Arc::new(state),
         })
     }
+}
 
-    pub async fn write_image(&self, canvas_address: Address, png_data: Vec<u8>) -> Result<()> {
-        let extension = "png";
-        let filename = format!("{}.{}", canvas_address, extension);
-
-        std::fs::write(self.config.flush_dir.join(filename), png_data).context(format!(
-            "Failed to write image for {} to disk",
-            canvas_address
-        ))?;
+#[async_trait]
+impl FlusherTrait for LocalFlusher {
+    fn state(&self) -> &FlusherState {
+        &self.state
+    }
 
+    /// We write to a temporary file and rename it into place, so readers never see
+    /// a partially written file. There's nowhere to keep the metadata.
+    async fn put_object(
+        &self,
+        name: &str,
+        _content_type: &str,
+        data: Vec<u8>,
+        _metadata: HashMap<String, String>,
+    ) -> Result<()> {
+        let path = self.config.flush_dir.join(name);
+        let tmp_path = self.config.flush_dir.join(format!("{}.tmp", name));
+        std::fs::write(&tmp_path, data)
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to move {} into place", tmp_path.display()))?;
         Ok(())
     }
-}
 
-#[async_trait]
-impl FlusherTrait for LocalFlusher {
-    fn get_interval(&self) -> Duration {
-        self.config.flush_interval
+    async fn head_object(&self, name: &str) -> Result<Option<HashMap<String, String>>> {
+        let path = self.config.flush_dir.join(name);
+        let exists = path
+            .try_exists()
+            .with_context(|| format!("Failed to check for {}", path.display()))?;
+        Ok(exists.then(HashMap::new))
     }
 
-    async fn flush(&self) -> Result<()> {
-        let pngs = self.pixel_storage.get_canvases_as_pngs().await?;
-        for (canvas_address, png_data) in pngs {
-            self.write_image(canvas_address, png_data).await?;
+    async fn get_object(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.config.flush_dir.join(name);
+        match std::fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
         }
-        Ok(())
+    }
+
+    /// We just give the path of the file.
+    fn object_url(&self, name: &str) -> String {
+        self.config.flush_dir.join(name).display().to_string()
+    }
+
+    fn image_object_name(&self, image_name: &str, format: ImageFormat) -> String {
+        format!("{}.{}", image_name, format.extension())
     }
 }
@@ -0,0 +1,44 @@
+// Alongside the images, each flusher publishes `manifest.json`, an index of every
+// canvas it published and where to find its images. Consumers can read it to find
+// out which canvases exist and how fresh each image is without listing the bucket.
+//
+// We write the manifest after every image in a flush has been written, so anything
+// it points to is already there. It's a single object, so readers see either the
+// old or the new manifest, never part of one. We only write it when it changed.
+//
+// `updated_at_secs` is when the flusher published the images of the canvas as they
+// are now, not when the txn was applied. When we restart we read the manifest we
+// published before, so canvases we don't need to upload again keep their time.
+
+use aptos_move_graphql_scalars::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The name of the manifest, next to the `images/` the flushers upload.
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Every canvas a flusher published.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Manifest {
+    /// Ordered by address.
+    pub canvases: Vec<ManifestCanvas>,
+}
+
+/// A canvas as a flusher last published it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ManifestCanvas {
+    pub canvas_address: Address,
+    pub width: u32,
+    pub height: u32,
+    /// The version of the last txn applied to the canvas when we published it.
+    pub last_applied_version: u64,
+    /// When we published the images, in seconds since the unix epoch.
+    pub updated_at_secs: u64,
+    /// The hash of the full size PNG and the config we published it with, see
+    /// `tracker.rs`.
+    pub content_hash: String,
+    /// Where to get the full size image, keyed by format extension.
+    pub images: BTreeMap<String, String>,
+    /// Where to get each rendition, keyed by rendition name then format extension.
+    pub renditions: BTreeMap<String, BTreeMap<String, String>>,
+}
@@ -0,0 +1,217 @@
+use super::{FlusherState, FlusherTrait, RenditionConfig, RetryConfig};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    config::{Builder, Credentials, Region},
+    operation::{get_object::GetObjectError, head_object::HeadObjectError},
+    primitives::ByteStream,
+    Client,
+};
+use pixel_storage::{ImageFormat, PixelStorageTrait};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct S3FlusherConfig {
+    bucket_name: String,
+    /// Where to send requests, e.g. `https://<account_id>.r2.cloudflarestorage.com`
+    /// for Cloudflare R2 or `http://localhost:9000` for a local MinIO. If not set we
+    /// use AWS S3.
+    #[serde(default)]
+    endpoint_url: Option<String>,
+    /// If not set we get the region from the environment, e.g. `AWS_REGION`. R2
+    /// expects `auto`.
+    #[serde(default)]
+    region: Option<String>,
+    /// If not set we get credentials from the environment, e.g.
+    /// `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, or the instance profile.
+    #[serde(default)]
+    credentials: Option<S3Credentials>,
+    /// Put the bucket in the path rather than the hostname. MinIO generally needs
+    /// this.
+    #[serde(default)]
+    force_path_style: bool,
+    /// Where the bucket is publicly served from, for the URLs in the manifest, e.g.
+    /// `https://pub-<id>.r2.dev` for R2. If not set we use the URL of the bucket.
+    #[serde(default)]
+    public_url: Option<String>,
+    #[serde(default = "S3FlusherConfig::default_flush_interval")]
+    flush_interval: Duration,
+    /// Scaled copies of each image to upload alongside the full image.
+    #[serde(default)]
+    renditions: Vec<RenditionConfig>,
+    /// The formats to publish each image in. Raw images are only published for
+    /// renditions that scale up, since raw images can't be scaled down.
+    #[serde(default = "S3FlusherConfig::default_image_formats")]
+    image_formats: Vec<ImageFormat>,
+    /// How to retry after failing to flush.
+    #[serde(default)]
+    retry: RetryConfig,
+}
+
+impl S3FlusherConfig {
+    pub fn default_flush_interval() -> Duration {
+        Duration::from_millis(1200)
+    }
+
+    pub fn default_image_formats() -> Vec<ImageFormat> {
+        vec![ImageFormat::Png]
+    }
+
+    fn public_url(&self) -> String {
+        match (&self.public_url, &self.endpoint_url) {
+            (Some(public_url), _) => public_url.trim_end_matches('/').to_string(),
+            (None, Some(endpoint_url)) => {
+                format!(
+                    "{}/{}",
+                    endpoint_url.trim_end_matches('/'),
+                    self.bucket_name
+                )
+            },
+            (None, None) => format!("https://{}.s3.amazonaws.com", self.bucket_name),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct S3Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+// We don't want the secret showing up in logs.
+impl std::fmt::Debug for S3Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Uploads images to an S3 compatible object store, e.g. AWS S3, Cloudflare R2, or
+/// MinIO. Images are laid out in the bucket the same way as for the GcsFlusher.
+#[derive(Clone)]
+pub struct S3Flusher {
+    config: S3FlusherConfig,
+    client: Client,
+    state: Arc<FlusherState>,
+}
+
+impl S3Flusher {
+    pub async fn new(
+        config: S3FlusherConfig,
+        pixel_storage: Arc<dyn PixelStorageTrait>,
+    ) -> Result<Self> {
+        let state = FlusherState::new(
+            "s3",
+            pixel_storage,
+            config.flush_interval,
+            &config.renditions,
+            &config.image_formats,
+            &config.retry,
+        )?;
+
+        let mut loader = aws_config::from_env();
+        if let Some(region) = &config.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        let sdk_config = loader.load().await;
+        let mut builder = Builder::from(&sdk_config).force_path_style(config.force_path_style);
+        if let Some(endpoint_url) = &config.endpoint_url {
+            builder = builder.endpoint_url(endpoint_url);
+        }
+        if let Some(credentials) = &config.credentials {
+            builder = builder.credentials_provider(Credentials::new(
+                &credentials.access_key_id,
+                &credentials.secret_access_key,
+                None,
+                None,
+                "s3_flusher_config",
+            ));
+        }
+        let client = Client::from_conf(builder.build());
+
+        Ok(Self {
+            config,
+            client,
+            state: Arc::new(state),
+        })
+    }
+}
+
+#[async_trait]
+impl FlusherTrait for S3Flusher {
+    fn state(&self) -> &FlusherState {
+        &self.state
+    }
+
+    async fn put_object(
+        &self,
+        name: &str,
+        content_type: &str,
+        data: Vec<u8>,
+        metadata: HashMap<String, String>,
+    ) -> Result<()> {
+        let mut request = self
+            .client
+            .put_object()
+            .bucket(&self.config.bucket_name)
+            .key(name)
+            .content_type(content_type)
+            // Don't let the content be cached anywhere.
+            .cache_control("no-cache, no-store, max-age=0")
+            .body(ByteStream::from(data));
+        for (key, value) in metadata {
+            request = request.metadata(key, value);
+        }
+        request.send().await.context("Failed to upload to S3")?;
+        Ok(())
+    }
+
+    async fn head_object(&self, name: &str) -> Result<Option<HashMap<String, String>>> {
+        let result = self
+            .client
+            .head_object()
+            .bucket(&self.config.bucket_name)
+            .key(name)
+            .send()
+            .await;
+        match result {
+            Ok(output) => Ok(Some(output.metadata().cloned().unwrap_or_default())),
+            Err(e) => match e.into_service_error() {
+                HeadObjectError::NotFound(_) => Ok(None),
+                e => Err(e).context("Failed to get object metadata from S3"),
+            },
+        }
+    }
+
+    async fn get_object(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.config.bucket_name)
+            .key(name)
+            .send()
+            .await;
+        let output = match result {
+            Ok(output) => output,
+            Err(e) => {
+                return match e.into_service_error() {
+                    GetObjectError::NoSuchKey(_) => Ok(None),
+                    e => Err(e).context("Failed to get object from S3"),
+                }
+            },
+        };
+        let data = output
+            .body
+            .collect()
+            .await
+            .context("Failed to read object from S3")?
+            .into_bytes();
+        Ok(Some(data.to_vec()))
+    }
+
+    fn object_url(&self, name: &str) -> String {
+        format!("{}/{}", self.config.public_url(), name)
+    }
+}
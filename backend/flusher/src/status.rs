@@ -0,0 +1,106 @@
+// When a flush fails we retry with exponential backoff, plus some jitter so that
+// flushers that failed together (e.g. because the bucket was unreachable) don't
+// retry in lockstep. A successful flush resets the backoff and the failure count,
+// so only failures in a row count towards the failure budget. Once a flusher uses
+// up its budget it gives up, which shuts down the service, see main.rs.
+//
+// Each flusher keeps its status in a `FlusherStatusTracker`, which the API reads to
+// report it from the health endpoint.
+
+use crate::unix_time_secs;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{sync::Mutex, time::Duration};
+
+/// How a flusher retries after failing to flush.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// How long to wait after the first failure. This doubles with each failure in
+    /// a row, up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after failing this many times in a row.
+    pub max_consecutive_failures: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(300),
+            max_consecutive_failures: 20,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// How long to wait before flushing again after failing this many times in a
+    /// row. We pick a random time between half the backoff and the full backoff.
+    pub fn backoff(&self, consecutive_failures: u32) -> Duration {
+        let exponent = consecutive_failures.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// The status of a flusher, as reported by the health endpoint.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FlusherStatus {
+    pub name: String,
+    /// When we last flushed successfully, in seconds since the unix epoch.
+    pub last_success_time_secs: Option<u64>,
+    /// The error from the last failed flush, if it failed since the last success.
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    pub max_consecutive_failures: u32,
+}
+
+impl FlusherStatus {
+    /// Whether the last flush succeeded, or there hasn't been one yet. A flusher
+    /// that used up its failure budget has given up, so it stays unhealthy.
+    pub fn is_healthy(&self) -> bool {
+        self.last_error.is_none() && self.consecutive_failures < self.max_consecutive_failures
+    }
+}
+
+/// Keeps track of the status of a flusher.
+#[derive(Debug)]
+pub struct FlusherStatusTracker {
+    status: Mutex<FlusherStatus>,
+}
+
+impl FlusherStatusTracker {
+    pub fn new(name: &str, retry_config: &RetryConfig) -> Self {
+        Self {
+            status: Mutex::new(FlusherStatus {
+                name: name.to_string(),
+                max_consecutive_failures: retry_config.max_consecutive_failures,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn status(&self) -> FlusherStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn record_success(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.last_success_time_secs = Some(unix_time_secs());
+        status.last_error = None;
+        status.consecutive_failures = 0;
+    }
+
+    /// Record a failed flush, returning how many times in a row it has failed.
+    pub fn record_failure(&self, error: &anyhow::Error) -> u32 {
+        let mut status = self.status.lock().unwrap();
+        status.last_error = Some(format!("{:#}", error));
+        status.consecutive_failures += 1;
+        status.consecutive_failures
+    }
+}
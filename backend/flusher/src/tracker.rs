@@ -0,0 +1,136 @@
+// Flushers only upload canvases whose pixels changed since they last uploaded them.
+// Each flusher subscribes to the updates pixel storage publishes as it applies txns
+// and keeps the set of canvases they touched. A flush only looks at those canvases.
+//
+// The first flush after startup has no such set, and neither does a flush after we
+// fell behind and missed updates, so those look at every canvas. We tell which
+// changed by hashing the full size PNG of each, which is cheap to get since pixel
+// storage caches it until the canvas changes. Every other image we publish is made
+// from the same pixels, so if the PNG didn't change neither did they, unless the
+// renditions or formats we publish changed. So we hash those too, which means
+// changing them in the config uploads every canvas again. We hash the canvases in
+// the changed set too, since e.g. drawing a pixel in the color it already was
+// doesn't change the image.
+//
+// Each flusher keeps its own record of what it uploaded, so one flusher failing
+// doesn't make another skip or repeat uploads. After a restart the record is empty.
+// Flushers that keep the hash next to what they upload tell us what it was, so we
+// don't upload every canvas again just because we restarted.
+//
+// We also keep the manifest we last published, see `manifest.rs`.
+
+use crate::Manifest;
+use aptos_move_graphql_scalars::Address;
+use pixel_storage::CanvasUpdate;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::sync::{
+    broadcast::{error::TryRecvError, Receiver},
+    Mutex,
+};
+
+/// Hash the full size PNG of a canvas along with the config that decides what we
+/// publish from it, see `FlusherState::published_config`.
+pub fn hash_content(png_data: &[u8], published_config: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    // The length keeps the boundary between the two unambiguous.
+    hasher.update((png_data.len() as u64).to_le_bytes());
+    hasher.update(png_data);
+    hasher.update(published_config);
+    format!("{:x}", hasher.finalize())
+}
+
+/// The hash of each canvas as a flusher last uploaded it, and which canvases
+/// changed since.
+#[derive(Debug)]
+pub struct UploadTracker {
+    uploaded: Mutex<HashMap<Address, String>>,
+    manifest: Mutex<Option<Manifest>>,
+    changed: Mutex<ChangedCanvases>,
+}
+
+#[derive(Debug)]
+struct ChangedCanvases {
+    updates: Receiver<Arc<CanvasUpdate>>,
+    canvases: HashSet<Address>,
+    /// Whether we missed updates, so any canvas might have changed.
+    missed_updates: bool,
+}
+
+impl UploadTracker {
+    /// Track the canvases that change from now on, given a subscription to the
+    /// updates from pixel storage.
+    pub fn new(updates: Receiver<Arc<CanvasUpdate>>) -> Self {
+        Self {
+            uploaded: Default::default(),
+            manifest: Default::default(),
+            changed: Mutex::new(ChangedCanvases {
+                updates,
+                canvases: HashSet::new(),
+                missed_updates: false,
+            }),
+        }
+    }
+
+    /// Get the canvases that changed since we last flushed them. This returns None
+    /// if we don't know what changed, i.e. we haven't published a manifest since
+    /// startup or we missed updates, in which case every canvas has to be checked.
+    pub async fn changed_canvases(&self) -> Option<HashSet<Address>> {
+        let mut changed = self.changed.lock().await;
+        let changed = &mut *changed;
+        loop {
+            match changed.updates.try_recv() {
+                Ok(update) => {
+                    changed.canvases.insert(update.canvas_address);
+                },
+                Err(TryRecvError::Lagged(_)) => changed.missed_updates = true,
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+        if changed.missed_updates || self.manifest.lock().await.is_none() {
+            return None;
+        }
+        Some(changed.canvases.clone())
+    }
+
+    /// Record that we flushed the given canvases as they were when we got them from
+    /// `changed_canvases`, or every canvas if None.
+    pub async fn record_flushed(&self, canvases: Option<&HashSet<Address>>) {
+        let mut changed = self.changed.lock().await;
+        match canvases {
+            Some(canvases) => changed
+                .canvases
+                .retain(|canvas_address| !canvases.contains(canvas_address)),
+            None => {
+                changed.canvases.clear();
+                changed.missed_updates = false;
+            },
+        }
+    }
+
+    /// Get the hash of the canvas as we last uploaded it, if we have since startup.
+    pub async fn uploaded_hash(&self, canvas_address: &Address) -> Option<String> {
+        self.uploaded.lock().await.get(canvas_address).cloned()
+    }
+
+    /// Record that every image of the canvas was uploaded with the given hash.
+    pub async fn record_upload(&self, canvas_address: Address, content_hash: String) {
+        self.uploaded
+            .lock()
+            .await
+            .insert(canvas_address, content_hash);
+    }
+
+    /// Get the manifest we last published, if we have since startup.
+    pub async fn published_manifest(&self) -> Option<Manifest> {
+        self.manifest.lock().await.clone()
+    }
+
+    /// Record that we published the given manifest.
+    pub async fn record_manifest(&self, manifest: Manifest) {
+        *self.manifest.lock().await = Some(manifest);
+    }
+}
@@ -0,0 +1,84 @@
+// These tests upload to a local fake-gcs-server, see "Flushing to a local GCS
+// emulator" in the README for how to run one. They're ignored by default, run them
+// with `--ignored` and FAKE_GCS_URL set to where the emulator is listening, e.g.
+// `http://localhost:4443`. They expect the emulator to have a bucket named `graffio`.
+
+use aptos_move_graphql_scalars::Address;
+use flusher::{image_name, FlusherTrait, GcsFlusher};
+use pixel_storage::{
+    CreateCanvasIntent, ImageFormat, MemoryPixelStorage, Palettes, PixelStorageTrait, WritePixelIntent,
+};
+use serde_json::json;
+use std::{str::FromStr, sync::Arc};
+
+const BUCKET_NAME: &str = "graffio";
+
+fn emulator_url() -> String {
+    std::env::var("FAKE_GCS_URL").expect("FAKE_GCS_URL must be set to the emulator URL")
+}
+
+async fn build_storage(canvas_address: Address) -> Arc<dyn PixelStorageTrait> {
+    let palettes = Arc::new(Palettes::new(Default::default()).unwrap());
+    let storage = MemoryPixelStorage::new(Default::default(), palettes);
+    storage
+        .create_canvas(CreateCanvasIntent {
+            canvas_address,
+            width: 4,
+            height: 4,
+            default_color: 1,
+            txn_version: 10,
+            txn_timestamp_secs: 100,
+        })
+        .await
+        .unwrap();
+    storage
+        .write_pixels(vec![WritePixelIntent {
+            canvas_address,
+            index: 5,
+            color: 0,
+            artist_address: canvas_address,
+            txn_version: 11,
+            txn_timestamp_secs: 101,
+        }])
+        .await
+        .unwrap();
+    Arc::new(storage)
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_flush_to_emulator() {
+    let canvas_address = Address::from_str("0x6766735f656d756c61746f72").unwrap();
+    let storage = build_storage(canvas_address).await;
+    let config = serde_json::from_value(json!({
+        "bucket_name": BUCKET_NAME,
+        "endpoint_url": emulator_url(),
+        "auth": {"type": "anonymous"},
+    }))
+    .unwrap();
+    let flusher = GcsFlusher::new(config, storage).await.unwrap();
+    flusher.flush().await.unwrap();
+
+    let object_name =
+        flusher.image_object_name(&image_name(&canvas_address, None), ImageFormat::Png);
+    let content_hash = flusher
+        .head_object(&object_name)
+        .await
+        .unwrap()
+        .expect("Image should have been uploaded")
+        .remove("graffio-content-hash")
+        .expect("Image should have the content hash in its metadata");
+
+    let manifest = flusher
+        .get_published_manifest()
+        .await
+        .unwrap()
+        .expect("Manifest should have been published");
+    let canvas = manifest
+        .canvases
+        .iter()
+        .find(|canvas| canvas.canvas_address == canvas_address)
+        .expect("Manifest should list the canvas");
+    assert_eq!(canvas.content_hash, content_hash);
+    assert_eq!(canvas.last_applied_version, 11);
+}
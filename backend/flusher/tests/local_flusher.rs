@@ -0,0 +1,92 @@
+use aptos_move_graphql_scalars::Address;
+use flusher::{image_name, FlusherTrait, LocalFlusher};
+use pixel_storage::{
+    CreateCanvasIntent, ImageFormat, MemoryPixelStorage, Palettes, PixelStorageTrait,
+    WritePixelIntent,
+};
+use serde_json::json;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+async fn create_canvas(storage: &MemoryPixelStorage, canvas_address: Address, txn_version: u64) {
+    storage
+        .create_canvas(CreateCanvasIntent {
+            canvas_address,
+            width: 4,
+            height: 4,
+            default_color: 1,
+            txn_version,
+            txn_timestamp_secs: 100,
+        })
+        .await
+        .unwrap();
+}
+
+fn image_path(flusher: &LocalFlusher, flush_dir: &Path, canvas_address: &Address) -> PathBuf {
+    flush_dir.join(flusher.image_object_name(&image_name(canvas_address, None), ImageFormat::Png))
+}
+
+async fn last_applied_versions(flusher: &LocalFlusher) -> Vec<(Address, u64)> {
+    flusher
+        .get_published_manifest()
+        .await
+        .unwrap()
+        .expect("Manifest should have been published")
+        .canvases
+        .iter()
+        .map(|canvas| (canvas.canvas_address, canvas.last_applied_version))
+        .collect()
+}
+
+/// After the first flush, only the canvases that changed are written again.
+#[tokio::test]
+async fn test_flush_changed_canvases() {
+    let flush_dir =
+        std::env::temp_dir().join(format!("graffio-local-flusher-{}", std::process::id()));
+    let first = Address::from_str("0x1").unwrap();
+    let second = Address::from_str("0x2").unwrap();
+    std::fs::create_dir_all(&flush_dir).unwrap();
+    let palettes = Arc::new(Palettes::new(Default::default()).unwrap());
+    let storage = Arc::new(MemoryPixelStorage::new(Default::default(), palettes));
+    create_canvas(&storage, first, 10).await;
+    create_canvas(&storage, second, 20).await;
+
+    let config = serde_json::from_value(json!({ "flush_dir": flush_dir })).unwrap();
+    let flusher = LocalFlusher::new(config, storage.clone()).await.unwrap();
+    flusher.flush().await.unwrap();
+    assert_eq!(last_applied_versions(&flusher).await, [
+        (first, 10),
+        (second, 20)
+    ]);
+
+    // Remove the images so we can tell which get written again.
+    std::fs::remove_file(image_path(&flusher, &flush_dir, &first)).unwrap();
+    std::fs::remove_file(image_path(&flusher, &flush_dir, &second)).unwrap();
+    storage
+        .write_pixels(vec![WritePixelIntent {
+            canvas_address: first,
+            index: 5,
+            color: 0,
+            artist_address: first,
+            txn_version: 21,
+            txn_timestamp_secs: 101,
+        }])
+        .await
+        .unwrap();
+    flusher.flush().await.unwrap();
+    assert!(image_path(&flusher, &flush_dir, &first).exists());
+    assert!(!image_path(&flusher, &flush_dir, &second).exists());
+    assert_eq!(last_applied_versions(&flusher).await, [
+        (first, 21),
+        (second, 20)
+    ]);
+
+    std::fs::remove_file(image_path(&flusher, &flush_dir, &first)).unwrap();
+    flusher.flush().await.unwrap();
+    assert!(!image_path(&flusher, &flush_dir, &first).exists());
+
+    std::fs::remove_dir_all(&flush_dir).unwrap();
+}
@@ -11,6 +11,9 @@ use std::fmt::Debug;
 #[async_trait::async_trait]
 pub trait MetadataStorageTrait: Debug + Send + Sync + 'static {
     async fn update_attribution(&self, intent: UpdateAttributionIntent) -> Result<()>;
+    /// Delete all attribution information for the given canvas, e.g. because the
+    /// canvas was cleared.
+    async fn delete_attribution(&self, canvas_address: &Address) -> Result<()>;
 }
 
 /// All the information necessary to update attribution in storage.
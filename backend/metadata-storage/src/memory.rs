@@ -1,5 +1,6 @@
 use super::{MetadataStorageTrait, UpdateAttributionIntent};
 use anyhow::Result;
+use aptos_move_graphql_scalars::Address;
 use aptos_processor_framework::StorageTrait;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -57,4 +58,8 @@ impl MetadataStorageTrait for MemoryMetadataStorage {
     async fn update_attribution(&self, _intent: UpdateAttributionIntent) -> Result<()> {
         Ok(())
     }
+
+    async fn delete_attribution(&self, _canvas_address: &Address) -> Result<()> {
+        Ok(())
+    }
 }
@@ -1,11 +1,12 @@
 use super::{MetadataStorageTrait, UpdateAttributionIntent};
 use anyhow::{Context, Result};
+use aptos_move_graphql_scalars::Address;
 use aptos_processor_framework::StorageTrait;
 use entities::{chain_id, last_processed_version, pixel_attribution};
 use migrations::{Migrator, MigratorTrait};
 use sea_orm::{
-    sea_query::OnConflict, ConnectionTrait, Database, DatabaseConnection, DbBackend, EntityTrait,
-    QueryTrait,
+    sea_query::OnConflict, ColumnTrait, ConnectionTrait, Database, DatabaseConnection, DbBackend,
+    EntityTrait, QueryFilter, QueryTrait,
 };
 use serde::{Deserialize, Serialize};
 use tracing::info;
@@ -79,6 +80,19 @@ impl MetadataStorageTrait for PostgresMetadataStorage {
 
         Ok(())
     }
+
+    async fn delete_attribution(&self, canvas_address: &Address) -> Result<()> {
+        let query = pixel_attribution::Entity::delete_many()
+            .filter(pixel_attribution::Column::CanvasAddress.eq(canvas_address.to_string()))
+            .build(DbBackend::Postgres);
+
+        self.connection
+            .execute(query)
+            .await
+            .context("Failed to delete attribution")?;
+
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
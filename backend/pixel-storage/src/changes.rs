@@ -0,0 +1,241 @@
+// This module lets clients pick up the pixels that changed since they last looked at
+// a canvas rather than downloading the whole image again.
+//
+// Each canvas keeps its most recent pixel changes in memory. If a client asks for
+// changes from before what we have in memory we fall back to the history, if there
+// is one. If a canvas was cleared (or created) since, or there are too many changes
+// to be worth sending, we tell the client to fetch the full image instead.
+//
+// Clients that want changes as they happen can subscribe to `CanvasUpdates`, which
+// each storage publishes to as it applies txns. This is a broadcast channel, so a
+// client that falls too far behind misses updates rather than holding up the
+// processor. It finds out it missed updates when it next receives, at which point
+// it should start again from the full image.
+
+use crate::history::{HistoryEvent, HistoryRecord, HistorySource};
+use anyhow::Result;
+use aptos_move_graphql_scalars::Address;
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::ControlFlow,
+    sync::Arc,
+};
+use tokio::sync::broadcast;
+
+/// How many recent changes we keep in memory for each canvas.
+const MAX_RECENT_CHANGES: usize = 10_000;
+
+/// If more pixels than this changed, the client is better off with the full image.
+const MAX_CHANGES_PER_RESPONSE: usize = 10_000;
+
+/// How many updates a subscriber can fall behind before it starts missing them.
+const UPDATE_CHANNEL_CAPACITY: usize = 1024;
+
+/// A pixel that was drawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelChange {
+    pub index: u32,
+    /// The index of the color in the canvas' palette.
+    pub color: u8,
+    /// The version of the txn that drew the pixel.
+    pub version: u64,
+}
+
+/// What changed in a canvas after a given version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CanvasChanges {
+    /// Every pixel that changed, with only the latest change for each pixel, ordered
+    /// by version.
+    Changes {
+        /// The version of the last txn applied to the canvas, which is what to ask
+        /// for changes since next time.
+        last_version: u64,
+        changes: Vec<PixelChange>,
+    },
+    /// We can't give the changes, e.g. because the canvas was cleared since or we
+    /// don't know what happened that long ago. The client should get the full image.
+    FullImageRequired { last_version: u64 },
+}
+
+impl CanvasChanges {
+    pub fn last_version(&self) -> u64 {
+        match self {
+            CanvasChanges::Changes { last_version, .. } => *last_version,
+            CanvasChanges::FullImageRequired { last_version } => *last_version,
+        }
+    }
+}
+
+/// A change to a canvas as it is applied. Pixels that were drawn come as
+/// `CanvasChanges::Changes`, in the order they were drawn. Anything that changes
+/// the whole canvas, e.g. a clear, comes as `CanvasChanges::FullImageRequired`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanvasUpdate {
+    pub canvas_address: Address,
+    pub changes: CanvasChanges,
+}
+
+/// Publishes each update to canvases to everyone subscribed at the time.
+#[derive(Debug)]
+pub struct CanvasUpdates {
+    sender: broadcast::Sender<Arc<CanvasUpdate>>,
+}
+
+impl CanvasUpdates {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(UPDATE_CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Send the update to every subscriber. This never waits on subscribers, slow
+    /// ones just miss the oldest updates, see the top of this file.
+    pub fn publish(&self, canvas_address: Address, changes: CanvasChanges) {
+        // This only fails if there are no subscribers, which is fine.
+        let _ = self.sender.send(Arc::new(CanvasUpdate {
+            canvas_address,
+            changes,
+        }));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<CanvasUpdate>> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for CanvasUpdates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The most recent changes to a canvas.
+#[derive(Clone, Debug)]
+pub struct RecentChanges {
+    changes: VecDeque<PixelChange>,
+    /// We know about every change after this version. Before this we might have
+    /// dropped changes, or the canvas was cleared, or we weren't running.
+    complete_since: u64,
+}
+
+impl RecentChanges {
+    /// Start tracking changes to a canvas, where we know nothing about changes at or
+    /// before the given version. Zero means we don't know about any changes, e.g. for
+    /// legacy files that don't record the last applied version.
+    pub fn new(complete_since: u64) -> Self {
+        Self {
+            changes: VecDeque::new(),
+            complete_since,
+        }
+    }
+
+    pub fn record(&mut self, changes: impl IntoIterator<Item = PixelChange>) {
+        self.changes.extend(changes);
+        while self.changes.len() > MAX_RECENT_CHANGES {
+            if let Some(dropped) = self.changes.pop_front() {
+                self.complete_since = self.complete_since.max(dropped.version);
+            }
+        }
+    }
+
+    /// Every pixel was reset at this version, so earlier changes don't matter.
+    pub fn reset(&mut self, version: u64) {
+        self.changes.clear();
+        self.complete_since = version;
+    }
+
+    /// Get the changes after the given version, or None if we don't have all of
+    /// them in memory.
+    pub fn since(&self, since_version: u64, last_version: u64) -> Option<CanvasChanges> {
+        if self.complete_since == 0 || since_version < self.complete_since {
+            return None;
+        }
+        let changes = self
+            .changes
+            .iter()
+            .filter(|change| change.version > since_version)
+            .copied();
+        Some(compact_changes(changes, last_version))
+    }
+}
+
+/// Work out the changes after the given version from the history of a canvas. We
+/// compact the changes as we go and stop as soon as there are too many, so this
+/// never holds more than `MAX_CHANGES_PER_RESPONSE` changes however long the
+/// history is.
+pub fn changes_from_history(
+    history: HistorySource,
+    since_version: u64,
+    last_version: u64,
+) -> Result<CanvasChanges> {
+    let mut changes = LatestChanges::default();
+    let mut full_image_required = false;
+    history.for_each_record_until(|record: HistoryRecord| {
+        if record.txn_version <= since_version {
+            return Ok(ControlFlow::Continue(()));
+        }
+        match record.event {
+            HistoryEvent::Draw { index, color, .. } => changes.insert(PixelChange {
+                index,
+                color,
+                version: record.txn_version,
+            }),
+            HistoryEvent::Create { .. } | HistoryEvent::Clear { .. } => full_image_required = true,
+        }
+        if changes.len() > MAX_CHANGES_PER_RESPONSE {
+            full_image_required = true;
+        }
+        Ok(if full_image_required {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        })
+    })?;
+    if full_image_required {
+        return Ok(CanvasChanges::FullImageRequired { last_version });
+    }
+    Ok(changes.into_changes(last_version))
+}
+
+/// Keep only the latest change to each pixel, in the order they happened.
+fn compact_changes(
+    changes: impl IntoIterator<Item = PixelChange>,
+    last_version: u64,
+) -> CanvasChanges {
+    let mut latest = LatestChanges::default();
+    for change in changes {
+        latest.insert(change);
+    }
+    latest.into_changes(last_version)
+}
+
+/// The latest change to each pixel, along with the order they came in.
+#[derive(Default)]
+struct LatestChanges {
+    changes: HashMap<u32, (usize, PixelChange)>,
+    next_position: usize,
+}
+
+impl LatestChanges {
+    fn insert(&mut self, change: PixelChange) {
+        self.changes
+            .insert(change.index, (self.next_position, change));
+        self.next_position += 1;
+    }
+
+    /// How many pixels changed.
+    fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    fn into_changes(self, last_version: u64) -> CanvasChanges {
+        if self.changes.len() > MAX_CHANGES_PER_RESPONSE {
+            return CanvasChanges::FullImageRequired { last_version };
+        }
+        let mut changes = self.changes.into_values().collect::<Vec<_>>();
+        changes.sort_unstable_by_key(|(position, _)| *position);
+        CanvasChanges::Changes {
+            last_version,
+            changes: changes.into_iter().map(|(_, change)| change).collect(),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use aptos_move_graphql_scalars::Address;
+use thiserror::Error;
+
+/// The ways writing to pixel storage can fail. If a write fails because of a
+/// single bad pixel (`OutOfBounds` or `InvalidColor`) none of the pixels for that
+/// canvas were written. Callers can use `CanvasInfo::check_pixel` to find the bad
+/// pixels up front.
+#[derive(Debug, Error)]
+pub enum PixelStorageError {
+    #[error("Canvas {canvas_address} does not exist")]
+    CanvasNotFound { canvas_address: Address },
+
+    #[error(
+        "Pixel index {index} is out of bounds for canvas {canvas_address}, which is {width}x{height}"
+    )]
+    OutOfBounds {
+        canvas_address: Address,
+        index: u32,
+        width: u32,
+        height: u32,
+    },
+
+    #[error("Color {color} is not in the palette for canvas {canvas_address}")]
+    InvalidColor { canvas_address: Address, color: u8 },
+
+    #[error("Canvas {canvas_address} is archived and can't be changed")]
+    CanvasArchived { canvas_address: Address },
+
+    /// An image of the canvas was requested at a size we don't allow, see
+    /// `MAX_SCALED_SIZE`.
+    #[error(
+        "Scaled image would be {width}x{height}, the most we allow is {max}x{max}",
+        max = crate::MAX_SCALED_SIZE
+    )]
+    ScaledImageTooLarge { width: u32, height: u32 },
+
+    #[error("Canvas {canvas_address} is corrupt")]
+    CorruptCanvas {
+        canvas_address: Address,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+pub type PixelStorageResult<T> = Result<T, PixelStorageError>;
@@ -0,0 +1,587 @@
+// This module describes the format of the canvas files we keep on disk.
+//
+// A canvas file starts with a fixed size header, followed by the pixel data. How
+// each pixel is stored depends on the pixel encoding (see `PixelEncoding`). All
+// integers are little endian. The header looks like this:
+//
+// | Offset | Size | Field                                             |
+// |--------|------|---------------------------------------------------|
+// | 0      | 8    | Magic bytes, always `GRAFFIO\0`                   |
+// | 8      | 2    | Format version                                    |
+// | 10     | 2    | Header length                                     |
+// | 12     | 4    | Width                                             |
+// | 16     | 4    | Height                                            |
+// | 20     | 1    | Default color                                     |
+// | 21     | 1    | Pixel encoding                                    |
+// | 22     | 2    | Palette ID                                        |
+// | 24     | 8    | Last applied txn version                          |
+// | 32     | 28   | Reserved                                          |
+// | 60     | 4    | CRC32 checksum of the preceding 60 bytes          |
+//
+// The checksum only covers the header, not the pixel data, since the pixel data is
+// updated in place through the mmap on every write.
+//
+// The last applied txn version lets us skip txns we've already applied if the
+// processor replays them after a restart. It is updated after the pixels of each
+// batch are written, so everything at or before it is definitely in the file.
+//
+// Before the header existed, canvas files were just the pixel data followed by the
+// width and height as 8 bytes each, with every pixel stored as RGB. We call this the
+// legacy format. We can still read and write legacy files, and `upgrade_legacy_file`
+// converts them to the current format.
+//
+// A corrupt width or height can claim more pixel data than fits in memory, so we
+// check the size doesn't overflow whenever we read a header or write a new file.
+// Everything else can assume it doesn't.
+
+use crate::{palette::DEFAULT_PALETTE_ID, RgbColor};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+};
+
+pub const MAGIC: [u8; 8] = *b"GRAFFIO\0";
+pub const FORMAT_VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 64;
+
+/// Legacy files don't record the default color, so we use this value to say we
+/// don't know it.
+pub const UNKNOWN_DEFAULT_COLOR: u8 = u8::MAX;
+
+const LEGACY_TRAILER_LEN: usize = 16;
+const CHECKSUM_OFFSET: usize = HEADER_LEN - 4;
+
+/// How each pixel is stored in the file.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PixelEncoding {
+    /// Each pixel is stored as 3 bytes (r, g, b). The palette is applied when the
+    /// pixel is written, so changing the palette means rewriting the file.
+    #[default]
+    Rgb,
+    /// Each pixel is stored as 1 byte, the index of the color in the palette.
+    Indexed,
+    /// Each pixel is stored as 4 bits, the index of the color in the palette. The
+    /// pixel with the even index is in the high 4 bits of the byte. This only
+    /// supports palettes of up to 16 colors.
+    PackedIndexed,
+}
+
+/// A pixel as read back from the file. Depending on the encoding, this is either
+/// the final color or an index into the palette.
+#[derive(Clone, Debug)]
+pub enum StoredColor {
+    Rgb(RgbColor),
+    Index(u8),
+}
+
+impl PixelEncoding {
+    fn to_byte(self) -> u8 {
+        match self {
+            PixelEncoding::Rgb => 0,
+            PixelEncoding::Indexed => 1,
+            PixelEncoding::PackedIndexed => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0 => PixelEncoding::Rgb,
+            1 => PixelEncoding::Indexed,
+            2 => PixelEncoding::PackedIndexed,
+            other => bail!("Unknown pixel encoding {}", other),
+        })
+    }
+
+    /// How many bytes it takes to store this many pixels, or None if that doesn't
+    /// fit in a usize.
+    pub fn data_len(self, num_pixels: usize) -> Option<usize> {
+        match self {
+            PixelEncoding::Rgb => num_pixels.checked_mul(3),
+            PixelEncoding::Indexed => Some(num_pixels),
+            PixelEncoding::PackedIndexed => Some(num_pixels / 2 + num_pixels % 2),
+        }
+    }
+
+    /// The largest palette index this encoding can store.
+    pub fn max_index(self) -> u8 {
+        match self {
+            PixelEncoding::Rgb | PixelEncoding::Indexed => u8::MAX,
+            PixelEncoding::PackedIndexed => 0x0F,
+        }
+    }
+
+    /// Store a pixel. `index` is the color's index in the palette and `rgb` is the
+    /// color itself, each encoding uses whichever it needs.
+    pub fn write(self, pixels: &mut [u8], pixel: usize, index: u8, rgb: &RgbColor) {
+        match self {
+            PixelEncoding::Rgb => {
+                pixels[pixel * 3] = rgb.r;
+                pixels[pixel * 3 + 1] = rgb.g;
+                pixels[pixel * 3 + 2] = rgb.b;
+            },
+            PixelEncoding::Indexed => pixels[pixel] = index,
+            PixelEncoding::PackedIndexed => {
+                let byte = &mut pixels[pixel / 2];
+                if pixel % 2 == 0 {
+                    *byte = (*byte & 0x0F) | (index << 4);
+                } else {
+                    *byte = (*byte & 0xF0) | (index & 0x0F);
+                }
+            },
+        }
+    }
+
+    pub fn read(self, pixels: &[u8], pixel: usize) -> StoredColor {
+        match self {
+            PixelEncoding::Rgb => StoredColor::Rgb(RgbColor {
+                r: pixels[pixel * 3],
+                g: pixels[pixel * 3 + 1],
+                b: pixels[pixel * 3 + 2],
+            }),
+            PixelEncoding::Indexed => StoredColor::Index(pixels[pixel]),
+            PixelEncoding::PackedIndexed => {
+                let byte = pixels[pixel / 2];
+                if pixel % 2 == 0 {
+                    StoredColor::Index(byte >> 4)
+                } else {
+                    StoredColor::Index(byte & 0x0F)
+                }
+            },
+        }
+    }
+
+    /// Overwrite every pixel with the same color.
+    pub fn fill(self, pixels: &mut [u8], index: u8, rgb: &RgbColor) {
+        match self {
+            PixelEncoding::Rgb => {
+                for pixel in pixels.chunks_exact_mut(3) {
+                    pixel.copy_from_slice(&[rgb.r, rgb.g, rgb.b]);
+                }
+            },
+            PixelEncoding::Indexed => pixels.fill(index),
+            PixelEncoding::PackedIndexed => pixels.fill((index << 4) | (index & 0x0F)),
+        }
+    }
+}
+
+/// Everything we know about a canvas other than its pixels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanvasHeader {
+    pub width: u32,
+    pub height: u32,
+    pub default_color: u8,
+    pub pixel_encoding: PixelEncoding,
+    pub palette_id: u16,
+    /// The version of the last txn applied to this canvas. Zero means we don't
+    /// know, e.g. for legacy files.
+    pub last_applied_version: u64,
+}
+
+impl CanvasHeader {
+    pub fn new(width: u32, height: u32, default_color: u8, pixel_encoding: PixelEncoding) -> Self {
+        Self {
+            width,
+            height,
+            default_color,
+            pixel_encoding,
+            palette_id: DEFAULT_PALETTE_ID,
+            last_applied_version: 0,
+        }
+    }
+
+    pub fn num_pixels(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    /// How many bytes the pixel data takes up. This is only for headers we've
+    /// checked with `checked_data_len`, see the top of this file.
+    pub fn data_len(&self) -> usize {
+        self.checked_data_len()
+            .expect("Canvas size should have been checked")
+    }
+
+    /// How many bytes the pixel data takes up, or an error if that overflows.
+    fn checked_data_len(&self) -> Result<usize> {
+        (self.width as usize)
+            .checked_mul(self.height as usize)
+            .and_then(|num_pixels| self.pixel_encoding.data_len(num_pixels))
+            .with_context(|| {
+                format!(
+                    "Corrupt canvas file, a {}x{} canvas is too large",
+                    self.width, self.height
+                )
+            })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..8].copy_from_slice(&MAGIC);
+        bytes[8..10].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes[10..12].copy_from_slice(&(HEADER_LEN as u16).to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.width.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.height.to_le_bytes());
+        bytes[20] = self.default_color;
+        bytes[21] = self.pixel_encoding.to_byte();
+        bytes[22..24].copy_from_slice(&self.palette_id.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.last_applied_version.to_le_bytes());
+        let checksum = crc32fast::hash(&bytes[..CHECKSUM_OFFSET]);
+        bytes[CHECKSUM_OFFSET..].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN {
+            bail!("File is too short to contain a header");
+        }
+        if bytes[0..8] != MAGIC {
+            bail!("File does not start with the magic bytes");
+        }
+        let format_version = u16::from_le_bytes(bytes[8..10].try_into()?);
+        if format_version != FORMAT_VERSION {
+            bail!("Unsupported format version {}", format_version);
+        }
+        let header_len = u16::from_le_bytes(bytes[10..12].try_into()?);
+        if header_len as usize != HEADER_LEN {
+            bail!("Unexpected header length {}", header_len);
+        }
+        let checksum = u32::from_le_bytes(bytes[CHECKSUM_OFFSET..HEADER_LEN].try_into()?);
+        if checksum != crc32fast::hash(&bytes[..CHECKSUM_OFFSET]) {
+            bail!("Header checksum does not match");
+        }
+        Ok(Self {
+            width: u32::from_le_bytes(bytes[12..16].try_into()?),
+            height: u32::from_le_bytes(bytes[16..20].try_into()?),
+            default_color: bytes[20],
+            pixel_encoding: PixelEncoding::from_byte(bytes[21])?,
+            palette_id: u16::from_le_bytes(bytes[22..24].try_into()?),
+            last_applied_version: u64::from_le_bytes(bytes[24..32].try_into()?),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CanvasFormat {
+    /// The format described at the top of this file.
+    Current,
+    /// Pixel data followed by the width and height, with no header.
+    Legacy,
+}
+
+/// Describes where everything lives in a canvas file.
+#[derive(Clone, Debug)]
+pub struct CanvasLayout {
+    pub format: CanvasFormat,
+    pub header: CanvasHeader,
+}
+
+impl CanvasLayout {
+    /// The range of bytes in the file that hold the pixel data.
+    pub fn pixel_range(&self) -> Range<usize> {
+        let start = match self.format {
+            CanvasFormat::Current => HEADER_LEN,
+            CanvasFormat::Legacy => 0,
+        };
+        start..start + self.header.data_len()
+    }
+
+    /// How long the file should be.
+    pub fn file_len(&self) -> u64 {
+        (self.header.data_len() + self.extra_len()) as u64
+    }
+
+    /// How many bytes the file has besides the pixel data.
+    fn extra_len(&self) -> usize {
+        match self.format {
+            CanvasFormat::Current => HEADER_LEN,
+            CanvasFormat::Legacy => LEGACY_TRAILER_LEN,
+        }
+    }
+
+    /// Make sure the file is as long as the layout says it should be.
+    fn validate_len(&self, len: u64) -> Result<()> {
+        self.header
+            .checked_data_len()?
+            .checked_add(self.extra_len())
+            .context("Corrupt canvas file, the canvas is too large")?;
+        let expected_len = self.file_len();
+        if len != expected_len {
+            bail!(
+                "File is {} bytes but a {}x{} canvas should be {} bytes",
+                len,
+                self.header.width,
+                self.header.height,
+                expected_len
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Work out the layout of a canvas from its full contents, e.g. from an mmap.
+pub fn read_layout(data: &[u8]) -> Result<CanvasLayout> {
+    let layout = if data.starts_with(&MAGIC) {
+        CanvasLayout {
+            format: CanvasFormat::Current,
+            header: CanvasHeader::from_bytes(data)?,
+        }
+    } else {
+        read_legacy_layout(data)?
+    };
+    layout.validate_len(data.len() as u64)?;
+    Ok(layout)
+}
+
+/// Work out the layout of a canvas file without reading the whole thing.
+pub fn read_layout_from_file(file: &mut File) -> Result<CanvasLayout> {
+    let len = file
+        .metadata()
+        .context("Failed to read file metadata")?
+        .len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut magic = [0; MAGIC.len()];
+    let starts_with_magic = len >= HEADER_LEN as u64 && {
+        file.read_exact(&mut magic)
+            .context("Failed to read magic")?;
+        magic == MAGIC
+    };
+    let layout = if starts_with_magic {
+        let mut header = [0; HEADER_LEN];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)
+            .context("Failed to read header")?;
+        CanvasLayout {
+            format: CanvasFormat::Current,
+            header: CanvasHeader::from_bytes(&header)?,
+        }
+    } else {
+        if len < LEGACY_TRAILER_LEN as u64 {
+            bail!("File is too short to contain width and height");
+        }
+        let mut trailer = [0; LEGACY_TRAILER_LEN];
+        file.seek(SeekFrom::End(-(LEGACY_TRAILER_LEN as i64)))
+            .context("Failed to seek to width and height")?;
+        file.read_exact(&mut trailer)
+            .context("Failed to read width and height")?;
+        read_legacy_layout(&trailer)?
+    };
+    layout.validate_len(len)?;
+    Ok(layout)
+}
+
+/// Build the layout for a legacy file from a buffer that ends with the width and
+/// height trailer.
+fn read_legacy_layout(data: &[u8]) -> Result<CanvasLayout> {
+    let (width, height) = read_width_and_height(data)?;
+    let (width, height) = match (u32::try_from(width), u32::try_from(height)) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => bail!("Width and height are too large ({}x{})", width, height),
+    };
+    Ok(CanvasLayout {
+        format: CanvasFormat::Legacy,
+        header: CanvasHeader::new(width, height, UNKNOWN_DEFAULT_COLOR, PixelEncoding::Rgb),
+    })
+}
+
+/// Read the width and height from the end of a legacy file.
+fn read_width_and_height(data: &[u8]) -> Result<(u64, u64)> {
+    let len = data.len();
+    if len < LEGACY_TRAILER_LEN {
+        bail!("File is too short to contain width and height");
+    }
+
+    let width_bytes: [u8; 8] = data[len - 16..len - 8]
+        .try_into()
+        .context("Failed to read width")?;
+    let height_bytes: [u8; 8] = data[len - 8..len]
+        .try_into()
+        .context("Failed to read height")?;
+
+    let width = u64::from_le_bytes(width_bytes);
+    let height = u64::from_le_bytes(height_bytes);
+
+    Ok((width, height))
+}
+
+/// Write a new canvas file in the current format where every pixel is the default
+/// color, `rgb` being what that color looks like in the palette.
+pub fn write_canvas_file(path: &Path, header: &CanvasHeader, rgb: &RgbColor) -> Result<()> {
+    let mut pixels = vec![0; header.checked_data_len()?];
+    header
+        .pixel_encoding
+        .fill(&mut pixels, header.default_color, rgb);
+    write_canvas_file_with_pixels(path, header, &pixels)
+}
+
+/// Write the file alongside where it should be and then rename it into place, so we
+/// never leave a partially written canvas file behind, and if there was already a
+/// file there it is untouched if this fails partway through.
+fn write_canvas_file_with_pixels(path: &Path, header: &CanvasHeader, pixels: &[u8]) -> Result<()> {
+    let mut data = Vec::with_capacity(HEADER_LEN + pixels.len());
+    data.extend(header.to_bytes());
+    data.extend(pixels);
+    let tmp_path = path.with_extension("canvas.tmp");
+    let mut file = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create file {}", tmp_path.display()))?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to move {} into place", tmp_path.display()))?;
+    Ok(())
+}
+
+/// If the file at the given path is in the legacy format, rewrite it in the current
+/// format. Returns whether the file was upgraded.
+pub fn upgrade_legacy_file(path: &Path) -> Result<bool> {
+    let mut file = File::open(path).context("Failed to open file")?;
+    let layout = read_layout_from_file(&mut file)?;
+    if layout.format != CanvasFormat::Legacy {
+        return Ok(false);
+    }
+
+    let mut pixels = vec![0; layout.pixel_range().len()];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut pixels)
+        .context("Failed to read pixel data")?;
+
+    write_canvas_file_with_pixels(path, &layout.header, &pixels)
+        .context("Failed to replace legacy file")?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn header() -> CanvasHeader {
+        CanvasHeader {
+            width: 3,
+            height: 2,
+            default_color: 4,
+            pixel_encoding: PixelEncoding::Indexed,
+            palette_id: 7,
+            last_applied_version: 1234,
+        }
+    }
+
+    fn temp_canvas_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "graffio-format-{}-{}.canvas",
+            std::process::id(),
+            name
+        ))
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = header();
+        assert_eq!(
+            CanvasHeader::from_bytes(&header.to_bytes()).unwrap(),
+            header
+        );
+    }
+
+    #[test]
+    fn test_header_checksum_mismatch() {
+        let mut bytes = header().to_bytes();
+        bytes[12] ^= 1;
+        let error = CanvasHeader::from_bytes(&bytes).unwrap_err();
+        assert!(error.to_string().contains("checksum"), "{}", error);
+    }
+
+    #[test]
+    fn test_read_layout() {
+        let header = header();
+        let mut data = header.to_bytes().to_vec();
+        data.extend([0; 6]);
+        let layout = read_layout(&data).unwrap();
+        assert_eq!(layout.format, CanvasFormat::Current);
+        assert_eq!(layout.header, header);
+        assert_eq!(layout.pixel_range(), HEADER_LEN..HEADER_LEN + 6);
+    }
+
+    #[test]
+    fn test_read_layout_truncated() {
+        let mut data = header().to_bytes().to_vec();
+        data.extend([0; 5]);
+        assert!(read_layout(&data).is_err());
+        assert!(read_layout(&data[..HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn test_read_layout_too_large() {
+        let mut header = header();
+        header.width = u32::MAX;
+        header.height = u32::MAX;
+        header.pixel_encoding = PixelEncoding::Rgb;
+        let error = read_layout(&header.to_bytes()).unwrap_err();
+        assert!(
+            error.to_string().contains("Corrupt canvas file"),
+            "{}",
+            error
+        );
+
+        let mut data = vec![0; 6];
+        data.extend(u64::from(u32::MAX).to_le_bytes());
+        data.extend(u64::from(u32::MAX).to_le_bytes());
+        let error = read_layout(&data).unwrap_err();
+        assert!(
+            error.to_string().contains("Corrupt canvas file"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_upgrade_legacy_file() {
+        let path = temp_canvas_file("legacy");
+        let pixels = (0..2 * 3 * 3).collect::<Vec<u8>>();
+        let mut data = pixels.clone();
+        data.extend(3u64.to_le_bytes());
+        data.extend(2u64.to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+        let layout = read_layout(&data).unwrap();
+        assert_eq!(layout.format, CanvasFormat::Legacy);
+        assert_eq!(layout.pixel_range(), 0..pixels.len());
+
+        assert!(upgrade_legacy_file(&path).unwrap());
+        let data = std::fs::read(&path).unwrap();
+        let layout = read_layout(&data).unwrap();
+        assert_eq!(layout.format, CanvasFormat::Current);
+        assert_eq!(
+            layout.header,
+            CanvasHeader::new(3, 2, UNKNOWN_DEFAULT_COLOR, PixelEncoding::Rgb)
+        );
+        assert_eq!(data[layout.pixel_range()], pixels);
+
+        assert!(!upgrade_legacy_file(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_layout_from_truncated_file() {
+        let path = temp_canvas_file("truncated");
+        let mut data = header().to_bytes().to_vec();
+        data.extend([0; 5]);
+        std::fs::write(&path, &data).unwrap();
+        let mut file = File::open(&path).unwrap();
+        assert!(read_layout_from_file(&mut file).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_packed_data_len() {
+        assert_eq!(PixelEncoding::PackedIndexed.data_len(5), Some(3));
+        assert_eq!(
+            PixelEncoding::PackedIndexed.data_len(usize::MAX),
+            Some(usize::MAX / 2 + 1)
+        );
+        assert_eq!(PixelEncoding::Rgb.data_len(usize::MAX), None);
+    }
+}
@@ -0,0 +1,475 @@
+// This module describes the history log we keep alongside each canvas file. It is
+// an append-only file of fixed size records, one for every change made to the
+// canvas, which lets us work out what the canvas looked like at any point in time.
+//
+// Each record is `RECORD_LEN` bytes. All integers are little endian:
+//
+// | Offset | Size | Field                                             |
+// |--------|------|---------------------------------------------------|
+// | 0      | 1    | Kind (0 = create, 1 = draw, 2 = clear)            |
+// | 1      | 1    | Color (index in the palette)                      |
+// | 2      | 2    | Reserved                                          |
+// | 4      | 4    | Pixel index, only used by draw                    |
+// | 8      | 8    | Txn version                                       |
+// | 16     | 8    | Txn timestamp in seconds                          |
+// | 24     | 32   | Artist address, only used by draw                 |
+//
+// If we crash partway through appending a record, the file ends with a partial
+// record. We ignore it when reading and cut it off before appending again, along
+// with any records for txns the canvas file doesn't have yet.
+
+use anyhow::{bail, Context, Result};
+use aptos_move_graphql_scalars::Address;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::ControlFlow,
+    path::Path,
+    str::FromStr,
+};
+
+pub const RECORD_LEN: usize = 56;
+
+/// A point in the history of a canvas.
+#[derive(Clone, Copy, Debug)]
+pub enum PointInTime {
+    /// Just after the txn with this version.
+    Version(u64),
+    /// Just after the last txn with a timestamp at or before this one.
+    TimestampSecs(u64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HistoryEvent {
+    Create {
+        default_color: u8,
+    },
+    Draw {
+        index: u32,
+        color: u8,
+        artist_address: Address,
+    },
+    Clear {
+        default_color: u8,
+    },
+}
+
+impl HistoryEvent {
+    /// The color the event sets pixels to.
+    pub fn color(&self) -> u8 {
+        match self {
+            HistoryEvent::Create { default_color } | HistoryEvent::Clear { default_color } => {
+                *default_color
+            },
+            HistoryEvent::Draw { color, .. } => *color,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryRecord {
+    pub txn_version: u64,
+    pub txn_timestamp_secs: u64,
+    pub event: HistoryEvent,
+}
+
+impl HistoryRecord {
+    /// Whether this record happened at or before the given point in time.
+    pub fn is_at_or_before(&self, point: PointInTime) -> bool {
+        match point {
+            PointInTime::Version(version) => self.txn_version <= version,
+            PointInTime::TimestampSecs(timestamp) => self.txn_timestamp_secs <= timestamp,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<[u8; RECORD_LEN]> {
+        let mut bytes = [0; RECORD_LEN];
+        match &self.event {
+            HistoryEvent::Create { default_color } => {
+                bytes[0] = 0;
+                bytes[1] = *default_color;
+            },
+            HistoryEvent::Draw {
+                index,
+                color,
+                artist_address,
+            } => {
+                bytes[0] = 1;
+                bytes[1] = *color;
+                bytes[4..8].copy_from_slice(&index.to_le_bytes());
+                bytes[24..56].copy_from_slice(&address_to_bytes(artist_address)?);
+            },
+            HistoryEvent::Clear { default_color } => {
+                bytes[0] = 2;
+                bytes[1] = *default_color;
+            },
+        }
+        bytes[8..16].copy_from_slice(&self.txn_version.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.txn_timestamp_secs.to_le_bytes());
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8; RECORD_LEN]) -> Result<Self> {
+        let color = bytes[1];
+        let event = match bytes[0] {
+            0 => HistoryEvent::Create {
+                default_color: color,
+            },
+            1 => HistoryEvent::Draw {
+                index: u32::from_le_bytes(bytes[4..8].try_into()?),
+                color,
+                artist_address: bytes_to_address(&bytes[24..56])?,
+            },
+            2 => HistoryEvent::Clear {
+                default_color: color,
+            },
+            other => bail!("Unknown history record kind {}", other),
+        };
+        Ok(Self {
+            txn_version: u64::from_le_bytes(bytes[8..16].try_into()?),
+            txn_timestamp_secs: u64::from_le_bytes(bytes[16..24].try_into()?),
+            event,
+        })
+    }
+}
+
+/// Where to read the history of a canvas from.
+#[derive(Clone, Copy, Debug)]
+pub enum HistorySource<'a> {
+    /// A history file in the format described at the top of this module.
+    File(&'a Path),
+    /// Records kept in memory, oldest first.
+    Records(&'a [HistoryRecord]),
+}
+
+impl HistorySource<'_> {
+    /// Call `f` on each record in order.
+    pub fn for_each_record(&self, mut f: impl FnMut(HistoryRecord) -> Result<()>) -> Result<()> {
+        self.for_each_record_until(|record| f(record).map(ControlFlow::Continue))
+    }
+
+    /// Call `f` on each record in order, stopping early if it returns `Break`.
+    pub fn for_each_record_until(
+        &self,
+        mut f: impl FnMut(HistoryRecord) -> Result<ControlFlow<()>>,
+    ) -> Result<()> {
+        match self {
+            HistorySource::File(path) => for_each_record(path, f),
+            HistorySource::Records(records) => {
+                for record in records.iter() {
+                    if f(record.clone())?.is_break() {
+                        break;
+                    }
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Start a new history file, replacing any existing one.
+pub fn create_history_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("Failed to create history file {}", path.display()))
+}
+
+/// Open an existing history file for appending, cutting off anything left behind by
+/// a crash: a partial record, or records after the last version applied to the
+/// canvas, see `is_applied`. We'd append those again when the txns are replayed.
+pub fn open_history_file(path: &Path, last_version: u64) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open history file {}", path.display()))?;
+    let len = file.metadata()?.len();
+    let mut valid_len = len - len % RECORD_LEN as u64;
+    // Records are in version order, so the ones to cut off are at the end.
+    let mut bytes = [0; RECORD_LEN];
+    while valid_len > 0 {
+        file.seek(SeekFrom::Start(valid_len - RECORD_LEN as u64))?;
+        file.read_exact(&mut bytes)
+            .context("Failed to read history file")?;
+        if is_applied(&HistoryRecord::from_bytes(&bytes)?, last_version) {
+            break;
+        }
+        valid_len -= RECORD_LEN as u64;
+    }
+    if valid_len != len {
+        file.set_len(valid_len)
+            .context("Failed to remove unapplied history records")?;
+    }
+    Ok(file)
+}
+
+/// Append the records to the history file. We write them all at once so a batch is
+/// either fully in the file or (after cutting off a partial record) not at all, at
+/// least for batches that fit in a single write.
+pub fn append_records(file: &mut File, records: &[HistoryRecord]) -> Result<()> {
+    let mut data = Vec::with_capacity(records.len() * RECORD_LEN);
+    for record in records {
+        data.extend(record.to_bytes()?);
+    }
+    file.write_all(&data)
+        .context("Failed to append to history file")
+}
+
+/// Read every complete record in the history file, calling `f` on each in order
+/// until it returns `Break`.
+fn for_each_record(
+    path: &Path,
+    mut f: impl FnMut(HistoryRecord) -> Result<ControlFlow<()>>,
+) -> Result<()> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open history file {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut bytes = [0; RECORD_LEN];
+    loop {
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => {
+                if f(HistoryRecord::from_bytes(&bytes)?)?.is_break() {
+                    return Ok(());
+                }
+            },
+            // This includes a partial record at the end of the file.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e).context("Failed to read history file"),
+        }
+    }
+}
+
+/// Take the complete records from the contents of a history file up to and including
+/// the given version, e.g. to match the last applied version of the canvas. A
+/// version of zero means we don't know what has been applied, so we keep every
+/// complete record.
+pub fn trim_history(data: &[u8], last_version: u64) -> Result<Vec<u8>> {
+    let mut trimmed = Vec::with_capacity(data.len());
+    for bytes in data.chunks_exact(RECORD_LEN) {
+        let record = HistoryRecord::from_bytes(bytes.try_into()?)?;
+        if !is_applied(&record, last_version) {
+            break;
+        }
+        trimmed.extend_from_slice(bytes);
+    }
+    Ok(trimmed)
+}
+
+/// Whether the record is for a txn the canvas has applied, given the last version
+/// applied to it. We append records before updating the version in the canvas
+/// file, so a crash in between leaves records for txns that will be replayed.
+fn is_applied(record: &HistoryRecord, last_version: u64) -> bool {
+    last_version == 0 || record.txn_version <= last_version
+}
+
+/// Apply a record to the pixels of a canvas, where the pixels are None if the canvas
+/// hasn't been created yet.
+pub fn apply_record(
+    pixels: &mut Option<Vec<u8>>,
+    num_pixels: usize,
+    record: &HistoryRecord,
+) -> Result<()> {
+    match (&record.event, &mut *pixels) {
+        (HistoryEvent::Create { default_color }, _)
+        | (HistoryEvent::Clear { default_color }, Some(_)) => {
+            *pixels = Some(vec![*default_color; num_pixels]);
+        },
+        (
+            HistoryEvent::Draw {
+                index,
+                color,
+                artist_address: _,
+            },
+            Some(pixels),
+        ) => {
+            let pixel = pixels.get_mut(*index as usize).with_context(|| {
+                format!(
+                    "Pixel index {} in history is out of bounds at version {}",
+                    index, record.txn_version
+                )
+            })?;
+            *pixel = *color;
+        },
+        (_, None) => bail!("History does not start with the creation of the canvas"),
+    }
+    Ok(())
+}
+
+/// Replay the history up to the given point in time, returning the color of every
+/// pixel as an index into the palette. The history must start with the creation of
+/// the canvas, otherwise we don't know what the canvas started as.
+pub fn replay(history: HistorySource, num_pixels: usize, point: PointInTime) -> Result<Vec<u8>> {
+    let mut pixels = None;
+    history.for_each_record(|record| {
+        if !record.is_at_or_before(point) {
+            return Ok(());
+        }
+        apply_record(&mut pixels, num_pixels, &record)
+    })?;
+    pixels.context("Canvas did not exist at that point in time")
+}
+
+fn address_to_bytes(address: &Address) -> Result<[u8; 32]> {
+    let hex = address.to_canonical_string();
+    let hex = hex.strip_prefix("0x").unwrap_or(&hex);
+    let mut bytes = [0; 32];
+    if hex.len() != 64 {
+        bail!("Unexpected address {}", hex);
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .with_context(|| format!("Unexpected address {}", hex))?;
+    }
+    Ok(bytes)
+}
+
+fn bytes_to_address(bytes: &[u8]) -> Result<Address> {
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    Address::from_str(&format!("0x{}", hex)).context("Failed to parse artist address")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn record(txn_version: u64, txn_timestamp_secs: u64, event: HistoryEvent) -> HistoryRecord {
+        HistoryRecord {
+            txn_version,
+            txn_timestamp_secs,
+            event,
+        }
+    }
+
+    fn draw(txn_version: u64, txn_timestamp_secs: u64, index: u32, color: u8) -> HistoryRecord {
+        record(txn_version, txn_timestamp_secs, HistoryEvent::Draw {
+            index,
+            color,
+            artist_address: Address::from_str("0x1").unwrap(),
+        })
+    }
+
+    fn records() -> Vec<HistoryRecord> {
+        vec![
+            record(10, 100, HistoryEvent::Create { default_color: 0 }),
+            draw(11, 100, 0, 1),
+            draw(12, 105, 1, 2),
+            record(13, 110, HistoryEvent::Clear { default_color: 3 }),
+            draw(14, 110, 2, 4),
+        ]
+    }
+
+    fn temp_history_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "graffio-history-{}-{}.history",
+            std::process::id(),
+            name
+        ));
+        let mut file = create_history_file(&path).unwrap();
+        append_records(&mut file, &records()).unwrap();
+        path
+    }
+
+    fn read_records(path: &Path) -> Vec<HistoryRecord> {
+        let mut records = Vec::new();
+        HistorySource::File(path)
+            .for_each_record(|record| {
+                records.push(record);
+                Ok(())
+            })
+            .unwrap();
+        records
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        for record in records() {
+            let bytes = record.to_bytes().unwrap();
+            assert_eq!(HistoryRecord::from_bytes(&bytes).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_open_truncates_partial_record() {
+        let path = temp_history_file("partial");
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&draw(15, 115, 3, 5).to_bytes().unwrap()[..RECORD_LEN / 2])
+            .unwrap();
+        drop(file);
+        assert_eq!(read_records(&path), records());
+
+        let mut file = open_history_file(&path, 14).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (records().len() * RECORD_LEN) as u64
+        );
+        append_records(&mut file, &[draw(15, 115, 3, 5)]).unwrap();
+        let mut expected = records();
+        expected.push(draw(15, 115, 3, 5));
+        assert_eq!(read_records(&path), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_truncates_unapplied_records() {
+        let path = temp_history_file("unapplied");
+        open_history_file(&path, 12).unwrap();
+        assert_eq!(read_records(&path), records()[..3]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_trim_history() {
+        let data = records()
+            .iter()
+            .flat_map(|record| record.to_bytes().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(trim_history(&data, 12).unwrap(), data[..3 * RECORD_LEN]);
+        assert_eq!(trim_history(&data, 0).unwrap(), data);
+        assert_eq!(
+            trim_history(&data[..data.len() - 1], 0).unwrap(),
+            data[..4 * RECORD_LEN]
+        );
+    }
+
+    #[test]
+    fn test_replay_to_version() {
+        let records = records();
+        let history = HistorySource::Records(&records);
+        assert_eq!(replay(history, 4, PointInTime::Version(10)).unwrap(), [
+            0, 0, 0, 0
+        ]);
+        assert_eq!(replay(history, 4, PointInTime::Version(12)).unwrap(), [
+            1, 2, 0, 0
+        ]);
+        assert_eq!(replay(history, 4, PointInTime::Version(14)).unwrap(), [
+            3, 3, 4, 3
+        ]);
+        assert!(replay(history, 4, PointInTime::Version(9)).is_err());
+    }
+
+    #[test]
+    fn test_replay_to_timestamp() {
+        let records = records();
+        let history = HistorySource::Records(&records);
+        assert_eq!(
+            replay(history, 4, PointInTime::TimestampSecs(104)).unwrap(),
+            [1, 0, 0, 0]
+        );
+        assert_eq!(
+            replay(history, 4, PointInTime::TimestampSecs(109)).unwrap(),
+            [1, 2, 0, 0]
+        );
+        assert_eq!(
+            replay(history, 4, PointInTime::TimestampSecs(110)).unwrap(),
+            [3, 3, 4, 3]
+        );
+        assert!(replay(history, 4, PointInTime::TimestampSecs(99)).is_err());
+    }
+}
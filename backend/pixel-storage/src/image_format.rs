@@ -0,0 +1,63 @@
+use crate::Scale;
+use serde::{Deserialize, Serialize};
+
+/// The formats we can encode an image of a canvas in.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    /// Lossless WebP.
+    Webp,
+    Bmp,
+    /// Our own format for clients that know the palette and want the smallest
+    /// uncompressed download. It is the width and height as little endian u32s,
+    /// followed by one byte per pixel, row by row from the top left, holding the
+    /// index of the color in the canvas' palette. Raw images can only be scaled up.
+    Raw,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 4] = [
+        ImageFormat::Png,
+        ImageFormat::Webp,
+        ImageFormat::Bmp,
+        ImageFormat::Raw,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::Raw => "raw",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Bmp => "image/bmp",
+            ImageFormat::Raw => "application/vnd.graffio.palette-indices",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.extension().eq_ignore_ascii_case(extension))
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.content_type().eq_ignore_ascii_case(content_type))
+    }
+
+    /// Whether we can encode an image scaled this way in this format. Palette
+    /// indices can't be averaged, so raw images can't be scaled down.
+    pub fn supports_scale(&self, scale: Scale) -> bool {
+        *self != ImageFormat::Raw || matches!(scale, Scale::Up(_) | Scale::Down(1))
+    }
+}
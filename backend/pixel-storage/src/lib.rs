@@ -1,94 +1,107 @@
+mod changes;
+mod error;
+mod format;
+mod history;
+mod image_format;
+mod memory;
 mod mmap;
+mod object;
+mod object_store;
+mod palette;
+mod timelapse;
 mod utils;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use aptos_move_graphql_scalars::Address;
-pub use mmap::{MmapPixelStorage, MmapPixelStorageConfig};
-use std::{collections::HashMap, fmt::Debug};
+pub use changes::{CanvasChanges, CanvasUpdate, CanvasUpdates, PixelChange};
+pub use error::{PixelStorageError, PixelStorageResult};
+pub use format::PixelEncoding;
+pub use history::PointInTime;
+pub use image_format::ImageFormat;
+pub use memory::{MemoryPixelStorage, MemoryPixelStorageConfig};
+pub use mmap::{
+    CanvasPixels, CanvasSnapshot, MmapPixelStorage, MmapPixelStorageConfig, PixelRepair,
+};
+pub use object::{ObjectPixelStorage, ObjectPixelStorageConfig};
+pub use object_store::{LocalObjectStore, LocalObjectStoreConfig, ObjectStore, ObjectStoreConfig};
+pub use palette::{Palettes, PalettesConfig, RgbColor};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+pub use timelapse::{FrameInterval, Timelapse, TimelapseFrame, TimelapseOptions};
+use tokio::sync::broadcast;
+use utils::check_in_bounds;
+pub use utils::MAX_SCALED_SIZE;
 
 /// Handles creating, updating, and reading canvases.
 #[async_trait::async_trait]
 pub trait PixelStorageTrait: Debug + Send + Sync + 'static {
-    async fn create_canvas(&self, intent: CreateCanvasIntent) -> Result<()>;
-    async fn write_pixels(&self, intent: Vec<WritePixelIntent>) -> Result<()>;
+    /// Create a canvas where every pixel is the default color. If the canvas
+    /// already exists this does nothing if the create has already been applied and
+    /// fails otherwise, it never wipes an existing canvas.
+    ///
+    /// Each canvas tracks the version of the last txn applied to it, and writes and
+    /// clears from txns at or before that version are skipped. This way, if the
+    /// processor replays txns after a restart, each is only applied once.
+    async fn create_canvas(&self, intent: CreateCanvasIntent) -> PixelStorageResult<()>;
+    /// Write pixels, which can be for several canvases. Every pixel for a canvas is
+    /// checked before any are written, so if one is invalid none of the pixels for
+    /// that canvas are written.
+    async fn write_pixels(&self, intent: Vec<WritePixelIntent>) -> PixelStorageResult<()>;
+    /// Reset every pixel in the canvas back to the default color.
+    async fn clear_canvas(&self, intent: ClearCanvasIntent) -> PixelStorageResult<()>;
+    /// Freeze the canvas so it can still be read but never written to again. Writes
+    /// from txns after the archive fail with `CanvasArchived`. Archiving a canvas
+    /// that is already archived does nothing.
+    async fn archive_canvas(&self, intent: ArchiveCanvasIntent) -> PixelStorageResult<()>;
+    /// Remove the canvas and everything we know about it, archived or not.
+    async fn delete_canvas(&self, canvas_address: &Address) -> PixelStorageResult<()>;
     async fn get_canvas_as_png(&self, canvas_address: &Address) -> Result<Vec<u8>>;
-    async fn get_canvases_as_pngs(&self) -> Result<HashMap<Address, Vec<u8>>>;
-}
-
-// In the contract we just use different int values to represent different colors,
-// for the sake of optimization.
-#[derive(Clone, Debug)]
-pub enum HardcodedColor {
-    Black = 0,
-    White = 1,
-    Blue = 2,
-    Green = 3,
-    Yellow = 4,
-    Orange = 5,
-    Red = 6,
-    Violet = 7,
-}
-
-impl From<u8> for HardcodedColor {
-    fn from(color: u8) -> Self {
-        match color {
-            0 => HardcodedColor::Black,
-            1 => HardcodedColor::White,
-            2 => HardcodedColor::Blue,
-            3 => HardcodedColor::Green,
-            4 => HardcodedColor::Yellow,
-            5 => HardcodedColor::Orange,
-            6 => HardcodedColor::Red,
-            7 => HardcodedColor::Violet,
-            _ => panic!("Invalid color: {}", color),
-        }
+    /// Render the whole canvas, scaled and encoded as requested.
+    async fn get_scaled_canvas_as_image(
+        &self,
+        canvas_address: &Address,
+        scale: Scale,
+        format: ImageFormat,
+    ) -> Result<Vec<u8>> {
+        let info = self.get_canvas_info(canvas_address).await?;
+        self.get_canvas_region_as_image(canvas_address, Region::full(&info), scale, format)
+            .await
     }
-}
+    /// Render part of the canvas, scaled and encoded as requested.
+    async fn get_canvas_region_as_image(
+        &self,
+        canvas_address: &Address,
+        region: Region,
+        scale: Scale,
+        format: ImageFormat,
+    ) -> Result<Vec<u8>>;
+    /// Render the canvas as it was at the given point in time, by replaying its
+    /// history from when it was created.
+    async fn get_canvas_as_png_at(
+        &self,
+        canvas_address: &Address,
+        point: PointInTime,
+    ) -> Result<Vec<u8>>;
+    /// Render the history of the canvas as a sequence of frames.
+    async fn get_canvas_timelapse(
+        &self,
+        canvas_address: &Address,
+        options: &TimelapseOptions,
+    ) -> Result<Timelapse>;
+    async fn get_canvases_as_pngs(&self) -> Result<HashMap<Address, Vec<u8>>>;
+    /// List every canvas in storage, including those not touched since startup.
+    async fn list_canvases(&self) -> Result<Vec<CanvasInfo>>;
+    async fn get_canvas_info(&self, canvas_address: &Address) -> Result<CanvasInfo>;
+    /// Get the pixels that changed after the given version, so clients can keep up
+    /// with a canvas without fetching the whole image each time. See `changes.rs`.
+    async fn get_canvas_changes(
+        &self,
+        canvas_address: &Address,
+        since_version: u64,
+    ) -> Result<CanvasChanges>;
 
-#[derive(Clone, Debug)]
-pub struct RgbColor {
-    r: u8,
-    g: u8,
-    b: u8,
-}
-
-impl From<&HardcodedColor> for RgbColor {
-    fn from(color: &HardcodedColor) -> Self {
-        match color {
-            HardcodedColor::Black => RgbColor { r: 0, g: 0, b: 0 },
-            HardcodedColor::White => RgbColor {
-                r: 255,
-                g: 255,
-                b: 255,
-            },
-            HardcodedColor::Blue => RgbColor {
-                r: 0,
-                g: 158,
-                b: 253,
-            },
-            HardcodedColor::Green => RgbColor { r: 0, g: 197, b: 3 },
-            HardcodedColor::Yellow => RgbColor {
-                r: 255,
-                g: 198,
-                b: 0,
-            },
-            HardcodedColor::Orange => RgbColor {
-                r: 255,
-                g: 125,
-                b: 0,
-            },
-            HardcodedColor::Red => RgbColor {
-                r: 250,
-                g: 0,
-                b: 106,
-            },
-            HardcodedColor::Violet => RgbColor {
-                r: 196,
-                g: 0,
-                b: 199,
-            },
-        }
-    }
+    /// Get every update applied to any canvas from now on, see `changes.rs`.
+    fn subscribe_to_updates(&self) -> broadcast::Receiver<Arc<CanvasUpdate>>;
 }
 
 /// All the information necessary to write a Pixel to storage.
@@ -97,7 +110,12 @@ pub struct WritePixelIntent {
     /// The address of the object containing the canvas.
     pub canvas_address: Address,
     pub index: u32,
-    pub color: HardcodedColor,
+    /// The index of the color in the canvas' palette.
+    pub color: u8,
+    /// The account that drew the pixel.
+    pub artist_address: Address,
+    pub txn_version: u64,
+    pub txn_timestamp_secs: u64,
 }
 
 /// All the information necessary to create a Canvas in storage.
@@ -107,5 +125,129 @@ pub struct CreateCanvasIntent {
     pub canvas_address: Address,
     pub width: u16,
     pub height: u16,
-    pub default_color: HardcodedColor,
+    /// The index of the default color in the canvas' palette.
+    pub default_color: u8,
+    pub txn_version: u64,
+    pub txn_timestamp_secs: u64,
+}
+
+/// All the information necessary to reset a Canvas in storage.
+#[derive(Clone, Debug)]
+pub struct ClearCanvasIntent {
+    /// The address of the object containing the canvas.
+    pub canvas_address: Address,
+    /// The index of the default color in the canvas' palette.
+    pub default_color: u8,
+    pub txn_version: u64,
+    pub txn_timestamp_secs: u64,
+}
+
+/// All the information necessary to archive a Canvas in storage.
+#[derive(Clone, Debug)]
+pub struct ArchiveCanvasIntent {
+    /// The address of the object containing the canvas.
+    pub canvas_address: Address,
+    /// The version of the txn that ended the canvas, e.g. by burning it. Zero if the
+    /// archive wasn't caused by a txn, e.g. when archiving a canvas by hand.
+    pub txn_version: u64,
+}
+
+/// Basic information about a canvas in storage.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CanvasInfo {
+    /// The address of the object containing the canvas.
+    pub canvas_address: Address,
+    pub width: u32,
+    pub height: u32,
+    /// The version of the last txn applied to the canvas.
+    pub last_applied_version: u64,
+    /// The palette the canvas was created with.
+    pub palette_id: u16,
+    /// Whether the canvas has been archived, in which case it never changes again.
+    pub archived: bool,
+}
+
+impl CanvasInfo {
+    /// Check that a pixel fits on the canvas and its color is in the canvas'
+    /// palette, the same way `write_pixels` does.
+    pub fn check_pixel(
+        &self,
+        palettes: &Palettes,
+        intent: &WritePixelIntent,
+    ) -> PixelStorageResult<()> {
+        check_in_bounds(intent, self.width, self.height)?;
+        palettes.color(self.palette_id, intent.color).map_err(|_| {
+            PixelStorageError::InvalidColor {
+                canvas_address: self.canvas_address,
+                color: intent.color,
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// A rectangle within a canvas, measured in canvas pixels from the top left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// The region covering the whole canvas.
+    pub fn full(info: &CanvasInfo) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: info.width,
+            height: info.height,
+        }
+    }
+
+    /// Whether the region is non-empty and entirely within a canvas of this size.
+    pub fn fits_within(&self, width: u32, height: u32) -> bool {
+        let fits = |start: u32, len: u32, max: u32| {
+            len > 0 && start.checked_add(len).is_some_and(|end| end <= max)
+        };
+        fits(self.x, self.width, width) && fits(self.y, self.height, height)
+    }
+}
+
+/// How to scale an image of a canvas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scale {
+    /// Each canvas pixel becomes a square this many pixels wide.
+    Up(u32),
+    /// Each square of canvas pixels this many pixels wide becomes one pixel, with the
+    /// color averaged over the square.
+    Down(u32),
+    /// Resize the image to this width, keeping the aspect ratio. Shrinking averages
+    /// the canvas pixels each image pixel covers, growing repeats canvas pixels.
+    ToWidth(u32),
+}
+
+impl Scale {
+    /// Build a scale from the `scale` and `width` options we accept in query params
+    /// and config. At most one of them can be set, if neither is the image is left
+    /// as is.
+    ///
+    /// This only checks the options themselves, whether the scaled image is small
+    /// enough depends on the size of the canvas, see `ScaledImageTooLarge`.
+    pub fn from_options(scale: Option<u32>, width: Option<u32>) -> Result<Self> {
+        match (scale, width) {
+            (Some(_), Some(_)) => bail!("Only one of scale and width can be set"),
+            (Some(0), None) | (None, Some(0)) => bail!("Scale and width must be at least 1"),
+            (Some(scale), None) if scale > MAX_SCALED_SIZE => {
+                bail!("Scale can be at most {}", MAX_SCALED_SIZE)
+            },
+            (None, Some(width)) if width > MAX_SCALED_SIZE => {
+                bail!("Width can be at most {}", MAX_SCALED_SIZE)
+            },
+            (Some(scale), None) => Ok(Scale::Up(scale)),
+            (None, Some(width)) => Ok(Scale::ToWidth(width)),
+            (None, None) => Ok(Scale::Up(1)),
+        }
+    }
 }
@@ -0,0 +1,491 @@
+use super::{
+    changes::{
+        changes_from_history, CanvasChanges, CanvasUpdate, CanvasUpdates, PixelChange,
+        RecentChanges,
+    },
+    history::{replay, HistoryEvent, HistoryRecord, HistorySource},
+    timelapse::render_timelapse,
+    utils::{
+        check_existing_canvas, encode_image, encode_raw, get_image, scale_image, scale_indices,
+        skip_applied,
+    },
+    ArchiveCanvasIntent, CanvasInfo, ClearCanvasIntent, CreateCanvasIntent, PixelStorageError,
+    PixelStorageResult, PixelStorageTrait, WritePixelIntent,
+};
+use crate::{ImageFormat, Palettes, PointInTime, Region, Scale, Timelapse, TimelapseOptions};
+use anyhow::{bail, Context, Result};
+use aptos_move_graphql_scalars::Address;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{broadcast, Mutex};
+use tracing::info;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryPixelStorageConfig {
+    /// If set, we keep every change to each canvas in memory, which lets us render
+    /// the canvas as it was at any point in time. This grows with every pixel
+    /// written, so it is best left off for long running instances.
+    #[serde(default)]
+    pub record_history: bool,
+}
+
+/// Everything about a canvas other than its history, e.g. to keep it somewhere else.
+#[derive(Clone, Debug)]
+pub(crate) struct CanvasState {
+    pub width: u32,
+    pub height: u32,
+    pub palette_id: u16,
+    /// The version of the last txn applied to the canvas.
+    pub last_applied_version: u64,
+    /// The color of each pixel as an index into the canvas' palette, row by row from
+    /// the top left.
+    pub pixels: Vec<u8>,
+    /// Whether the canvas has been archived, see `archive_canvas`.
+    pub archived: bool,
+}
+
+/// A canvas held in memory.
+#[derive(Clone, Debug)]
+struct MemoryCanvas {
+    width: u32,
+    height: u32,
+    palette_id: u16,
+    /// The color of each pixel as an index into the canvas' palette, row by row from
+    /// the top left.
+    pixels: Vec<u8>,
+    /// Every change made to the canvas, if we're recording history.
+    history: Option<Vec<HistoryRecord>>,
+    /// The version of the last txn applied to this canvas.
+    last_applied_version: u64,
+    /// The most recent pixels drawn, see `changes.rs`.
+    recent_changes: RecentChanges,
+    /// Archived canvases can be read but never change again.
+    archived: bool,
+}
+
+impl MemoryCanvas {
+    fn info(&self, canvas_address: &Address) -> CanvasInfo {
+        CanvasInfo {
+            canvas_address: *canvas_address,
+            width: self.width,
+            height: self.height,
+            last_applied_version: self.last_applied_version,
+            palette_id: self.palette_id,
+            archived: self.archived,
+        }
+    }
+
+    fn record_history(&mut self, records: impl IntoIterator<Item = HistoryRecord>) {
+        if let Some(history) = &mut self.history {
+            history.extend(records);
+        }
+    }
+
+    /// Read the palette indices of the pixels in the region row by row.
+    fn read_region(&self, region: Region) -> Result<Vec<u8>> {
+        if !region.fits_within(self.width, self.height) {
+            bail!(
+                "Region {:?} is not within the {}x{} canvas",
+                region,
+                self.width,
+                self.height
+            );
+        }
+        let mut data = Vec::with_capacity(region.width as usize * region.height as usize);
+        for y in region.y..region.y + region.height {
+            let row_start = y as usize * self.width as usize + region.x as usize;
+            data.extend_from_slice(&self.pixels[row_start..row_start + region.width as usize]);
+        }
+        Ok(data)
+    }
+}
+
+/// An in-memory, transient storage implementation. Nothing survives a restart, so
+/// this is only useful for tests and throwaway instances.
+#[derive(Debug)]
+pub struct MemoryPixelStorage {
+    config: MemoryPixelStorageConfig,
+    palettes: Arc<Palettes>,
+    canvases: Arc<Mutex<HashMap<Address, MemoryCanvas>>>,
+    updates: CanvasUpdates,
+}
+
+impl MemoryPixelStorage {
+    pub fn new(config: MemoryPixelStorageConfig, palettes: Arc<Palettes>) -> Self {
+        Self {
+            config,
+            palettes,
+            canvases: Arc::new(Mutex::new(HashMap::new())),
+            updates: CanvasUpdates::new(),
+        }
+    }
+
+    /// Get a copy of the canvas, so we don't hold the lock while encoding images.
+    async fn get_canvas(&self, canvas_address: &Address) -> PixelStorageResult<MemoryCanvas> {
+        self.canvases
+            .lock()
+            .await
+            .get(canvas_address)
+            .cloned()
+            .ok_or(PixelStorageError::CanvasNotFound {
+                canvas_address: *canvas_address,
+            })
+    }
+
+    /// Get the current state of the canvas.
+    pub(crate) async fn canvas_state(
+        &self,
+        canvas_address: &Address,
+    ) -> PixelStorageResult<CanvasState> {
+        let canvas = self.get_canvas(canvas_address).await?;
+        Ok(CanvasState {
+            width: canvas.width,
+            height: canvas.height,
+            palette_id: canvas.palette_id,
+            last_applied_version: canvas.last_applied_version,
+            pixels: canvas.pixels,
+            archived: canvas.archived,
+        })
+    }
+
+    /// Add a canvas from somewhere else, replacing any canvas at the same address.
+    /// We don't know anything about how the canvas got to this state, so there is no
+    /// history and no recent changes.
+    pub(crate) async fn insert_canvas(&self, canvas_address: Address, state: CanvasState) {
+        let canvas = MemoryCanvas {
+            width: state.width,
+            height: state.height,
+            palette_id: state.palette_id,
+            pixels: state.pixels,
+            history: None,
+            last_applied_version: state.last_applied_version,
+            recent_changes: RecentChanges::new(state.last_applied_version),
+            archived: state.archived,
+        };
+        self.canvases.lock().await.insert(canvas_address, canvas);
+    }
+
+    fn get_history<'a>(
+        canvas_address: &Address,
+        canvas: &'a MemoryCanvas,
+    ) -> Result<HistorySource<'a>> {
+        match &canvas.history {
+            Some(history) => Ok(HistorySource::Records(history)),
+            None => bail!("No history has been recorded for canvas {}", canvas_address),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PixelStorageTrait for MemoryPixelStorage {
+    async fn create_canvas(&self, intent: CreateCanvasIntent) -> PixelStorageResult<()> {
+        let mut canvases = self.canvases.lock().await;
+        if let Some(canvas) = canvases.get(&intent.canvas_address) {
+            return Ok(check_existing_canvas(&intent, canvas.last_applied_version)?);
+        }
+
+        let palette_id = self.palettes.palette_id_for(&intent.canvas_address);
+        self.palettes
+            .color(palette_id, intent.default_color)
+            .map_err(|_| PixelStorageError::InvalidColor {
+                canvas_address: intent.canvas_address,
+                color: intent.default_color,
+            })?;
+
+        let history = self.config.record_history.then(|| {
+            vec![HistoryRecord {
+                txn_version: intent.txn_version,
+                txn_timestamp_secs: intent.txn_timestamp_secs,
+                event: HistoryEvent::Create {
+                    default_color: intent.default_color,
+                },
+            }]
+        });
+        let canvas = MemoryCanvas {
+            width: intent.width as u32,
+            height: intent.height as u32,
+            palette_id,
+            pixels: vec![intent.default_color; intent.width as usize * intent.height as usize],
+            history,
+            last_applied_version: intent.txn_version,
+            recent_changes: RecentChanges::new(intent.txn_version),
+            archived: false,
+        };
+        canvases.insert(intent.canvas_address, canvas);
+        self.updates
+            .publish(intent.canvas_address, CanvasChanges::FullImageRequired {
+                last_version: intent.txn_version,
+            });
+
+        info!("Created canvas {}", intent.canvas_address);
+
+        Ok(())
+    }
+
+    async fn write_pixels(&self, intents: Vec<WritePixelIntent>) -> PixelStorageResult<()> {
+        let mut canvas_to_intents = HashMap::new();
+        for intent in intents.into_iter() {
+            canvas_to_intents
+                .entry(intent.canvas_address)
+                .or_insert_with(Vec::new)
+                .push(intent);
+        }
+
+        let mut canvases = self.canvases.lock().await;
+        for (canvas_address, intents) in canvas_to_intents.into_iter() {
+            let canvas = canvases
+                .get_mut(&canvas_address)
+                .ok_or(PixelStorageError::CanvasNotFound { canvas_address })?;
+            let intents = skip_applied(intents, canvas.last_applied_version, |intent| {
+                intent.txn_version
+            });
+            let last_version = match intents.iter().map(|intent| intent.txn_version).max() {
+                Some(version) => version,
+                None => continue,
+            };
+            if canvas.archived {
+                return Err(PixelStorageError::CanvasArchived { canvas_address });
+            }
+
+            // Check everything first so we don't write half the pixels if one of them
+            // is invalid.
+            let info = canvas.info(&canvas_address);
+            for intent in &intents {
+                info.check_pixel(&self.palettes, intent)?;
+            }
+
+            for intent in &intents {
+                canvas.pixels[intent.index as usize] = intent.color;
+            }
+            let changes = intents
+                .iter()
+                .map(|intent| PixelChange {
+                    index: intent.index,
+                    color: intent.color,
+                    version: intent.txn_version,
+                })
+                .collect::<Vec<_>>();
+            canvas.recent_changes.record(changes.iter().copied());
+            self.updates
+                .publish(canvas_address, CanvasChanges::Changes {
+                    last_version,
+                    changes,
+                });
+            canvas.record_history(intents.into_iter().map(|intent| HistoryRecord {
+                txn_version: intent.txn_version,
+                txn_timestamp_secs: intent.txn_timestamp_secs,
+                event: HistoryEvent::Draw {
+                    index: intent.index,
+                    color: intent.color,
+                    artist_address: intent.artist_address,
+                },
+            }));
+            canvas.last_applied_version = last_version;
+        }
+
+        Ok(())
+    }
+
+    async fn clear_canvas(&self, intent: ClearCanvasIntent) -> PixelStorageResult<()> {
+        let mut canvases = self.canvases.lock().await;
+        let canvas =
+            canvases
+                .get_mut(&intent.canvas_address)
+                .ok_or(PixelStorageError::CanvasNotFound {
+                    canvas_address: intent.canvas_address,
+                })?;
+        if intent.txn_version <= canvas.last_applied_version {
+            return Ok(());
+        }
+        if canvas.archived {
+            return Err(PixelStorageError::CanvasArchived {
+                canvas_address: intent.canvas_address,
+            });
+        }
+        self.palettes
+            .color(canvas.palette_id, intent.default_color)
+            .map_err(|_| PixelStorageError::InvalidColor {
+                canvas_address: intent.canvas_address,
+                color: intent.default_color,
+            })?;
+
+        canvas.pixels.fill(intent.default_color);
+        canvas.record_history([HistoryRecord {
+            txn_version: intent.txn_version,
+            txn_timestamp_secs: intent.txn_timestamp_secs,
+            event: HistoryEvent::Clear {
+                default_color: intent.default_color,
+            },
+        }]);
+        canvas.recent_changes.reset(intent.txn_version);
+        canvas.last_applied_version = intent.txn_version;
+        self.updates
+            .publish(intent.canvas_address, CanvasChanges::FullImageRequired {
+                last_version: intent.txn_version,
+            });
+
+        info!("Cleared canvas {}", intent.canvas_address);
+
+        Ok(())
+    }
+
+    async fn archive_canvas(&self, intent: ArchiveCanvasIntent) -> PixelStorageResult<()> {
+        let mut canvases = self.canvases.lock().await;
+        let canvas =
+            canvases
+                .get_mut(&intent.canvas_address)
+                .ok_or(PixelStorageError::CanvasNotFound {
+                    canvas_address: intent.canvas_address,
+                })?;
+        if canvas.archived {
+            return Ok(());
+        }
+        canvas.archived = true;
+        canvas.last_applied_version = canvas.last_applied_version.max(intent.txn_version);
+
+        info!("Archived canvas {}", intent.canvas_address);
+
+        Ok(())
+    }
+
+    async fn delete_canvas(&self, canvas_address: &Address) -> PixelStorageResult<()> {
+        let mut canvases = self.canvases.lock().await;
+        if canvases.remove(canvas_address).is_none() {
+            return Err(PixelStorageError::CanvasNotFound {
+                canvas_address: *canvas_address,
+            });
+        }
+
+        info!("Deleted canvas {}", canvas_address);
+
+        Ok(())
+    }
+
+    async fn get_canvas_as_png(&self, canvas_address: &Address) -> Result<Vec<u8>> {
+        self.get_scaled_canvas_as_image(canvas_address, Scale::Up(1), ImageFormat::Png)
+            .await
+    }
+
+    async fn get_canvas_region_as_image(
+        &self,
+        canvas_address: &Address,
+        region: Region,
+        scale: Scale,
+        format: ImageFormat,
+    ) -> Result<Vec<u8>> {
+        let (palette_id, indices) = {
+            let canvases = self.canvases.lock().await;
+            let canvas = canvases
+                .get(canvas_address)
+                .ok_or(PixelStorageError::CanvasNotFound {
+                    canvas_address: *canvas_address,
+                })?;
+            (canvas.palette_id, canvas.read_region(region)?)
+        };
+
+        if format == ImageFormat::Raw {
+            let (indices, width, height) =
+                scale_indices(indices, region.width, region.height, scale)?;
+            return Ok(encode_raw(&indices, width, height));
+        }
+
+        let data = indices
+            .into_iter()
+            .map(|index| self.palettes.color(palette_id, index).cloned())
+            .collect::<Result<Vec<_>>>()?;
+        let (data, width, height) = scale_image(data, region.width, region.height, scale)?;
+        encode_image(data, width, height, format)
+            .with_context(|| format!("Failed to convert data to {:?}", format))
+    }
+
+    async fn get_canvas_as_png_at(
+        &self,
+        canvas_address: &Address,
+        point: PointInTime,
+    ) -> Result<Vec<u8>> {
+        let canvas = self.get_canvas(canvas_address).await?;
+        let history = Self::get_history(canvas_address, &canvas)?;
+        let indices = replay(history, canvas.pixels.len(), point)
+            .with_context(|| format!("Failed to replay history for {}", canvas_address))?;
+
+        let data = indices
+            .into_iter()
+            .map(|index| self.palettes.color(canvas.palette_id, index).cloned())
+            .collect::<Result<Vec<_>>>()?;
+        get_image(data, canvas.width, canvas.height).context("Failed to convert data to a png")
+    }
+
+    async fn get_canvas_timelapse(
+        &self,
+        canvas_address: &Address,
+        options: &TimelapseOptions,
+    ) -> Result<Timelapse> {
+        let canvas = self.get_canvas(canvas_address).await?;
+        let history = Self::get_history(canvas_address, &canvas)?;
+        let palette = self.palettes.colors(canvas.palette_id)?;
+        render_timelapse(history, canvas.width, canvas.height, palette, options)
+            .with_context(|| format!("Failed to render timelapse for {}", canvas_address))
+    }
+
+    async fn get_canvases_as_pngs(&self) -> Result<HashMap<Address, Vec<u8>>> {
+        let addresses = {
+            let canvases = self.canvases.lock().await;
+            canvases.keys().copied().collect::<Vec<_>>()
+        };
+        let mut pngs = HashMap::new();
+        for address in addresses {
+            let png = self
+                .get_canvas_as_png(&address)
+                .await
+                .context(format!("Failed to get canvas {} as a png", address))?;
+            pngs.insert(address, png);
+        }
+        Ok(pngs)
+    }
+
+    async fn list_canvases(&self) -> Result<Vec<CanvasInfo>> {
+        let canvases = self.canvases.lock().await;
+        Ok(canvases
+            .iter()
+            .map(|(canvas_address, canvas)| canvas.info(canvas_address))
+            .collect())
+    }
+
+    async fn get_canvas_info(&self, canvas_address: &Address) -> Result<CanvasInfo> {
+        let canvases = self.canvases.lock().await;
+        let canvas = canvases
+            .get(canvas_address)
+            .ok_or(PixelStorageError::CanvasNotFound {
+                canvas_address: *canvas_address,
+            })?;
+        Ok(canvas.info(canvas_address))
+    }
+
+    async fn get_canvas_changes(
+        &self,
+        canvas_address: &Address,
+        since_version: u64,
+    ) -> Result<CanvasChanges> {
+        let canvases = self.canvases.lock().await;
+        let canvas = canvases
+            .get(canvas_address)
+            .ok_or(PixelStorageError::CanvasNotFound {
+                canvas_address: *canvas_address,
+            })?;
+        let last_version = canvas.last_applied_version;
+        if let Some(changes) = canvas.recent_changes.since(since_version, last_version) {
+            return Ok(changes);
+        }
+        match &canvas.history {
+            Some(history) => {
+                changes_from_history(HistorySource::Records(history), since_version, last_version)
+            },
+            None => Ok(CanvasChanges::FullImageRequired { last_version }),
+        }
+    }
+
+    fn subscribe_to_updates(&self) -> broadcast::Receiver<Arc<CanvasUpdate>> {
+        self.updates.subscribe()
+    }
+}
@@ -1,18 +1,41 @@
-use super::{utils::get_image, CreateCanvasIntent, PixelStorageTrait, WritePixelIntent};
-use crate::RgbColor;
-use anyhow::{Context, Result};
+use super::{
+    changes::{
+        changes_from_history, CanvasChanges, CanvasUpdate, CanvasUpdates, PixelChange,
+        RecentChanges,
+    },
+    format::{
+        read_layout, read_layout_from_file, upgrade_legacy_file, write_canvas_file, CanvasFormat,
+        CanvasHeader, CanvasLayout, PixelEncoding, StoredColor, HEADER_LEN,
+    },
+    history::{
+        append_records, create_history_file, open_history_file, replay, trim_history, HistoryEvent,
+        HistoryRecord, HistorySource,
+    },
+    timelapse::render_timelapse,
+    utils::{
+        check_existing_canvas, check_in_bounds, encode_image, encode_raw, get_image, scale_image,
+        scale_indices, skip_applied,
+    },
+    ArchiveCanvasIntent, CanvasInfo, ClearCanvasIntent, CreateCanvasIntent, PixelStorageError,
+    PixelStorageResult, PixelStorageTrait, WritePixelIntent,
+};
+use crate::{
+    ImageFormat, Palettes, PointInTime, Region, RgbColor, Scale, Timelapse, TimelapseOptions,
+};
+use anyhow::{bail, Context, Result};
 use aptos_move_graphql_scalars::Address;
-use memmap2::MmapMut;
+use memmap2::{MmapMut, MmapOptions};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
     fs::{File, OpenOptions},
-    io::Write,
     path::{Path, PathBuf},
+    str::FromStr,
     sync::Arc,
+    time::Instant,
 };
-use tokio::sync::Mutex;
-use tracing::{error, info};
+use tokio::sync::{broadcast, Mutex};
+use tracing::{info, warn};
 
 // There could be an alternate implementation where instead of using the mmap, for
 // every pixel we read the png, update the pixel, and write the png back to disk.
@@ -35,69 +58,689 @@ use tracing::{error, info};
 #[serde(deny_unknown_fields)]
 pub struct MmapPixelStorageConfig {
     pub storage_directory: PathBuf,
+
+    /// If set, canvas files in the legacy format (no header, width and height at the
+    /// end) are converted to the current format at startup. If not set we can still
+    /// read and write them as they are.
+    #[serde(default = "MmapPixelStorageConfig::default_upgrade_legacy_files")]
+    pub upgrade_legacy_files: bool,
+
+    /// How pixels are stored in newly created canvas files. Existing files keep
+    /// whatever encoding they were created with. The indexed encodings store palette
+    /// indices rather than RGB and are 3 (`indexed`) or 6 (`packed_indexed`) times
+    /// smaller than `rgb`.
+    #[serde(default)]
+    pub pixel_encoding: PixelEncoding,
+
+    /// If set, we keep a log of every change to each canvas next to its canvas file,
+    /// which lets us render the canvas as it was at any point in time. This is only
+    /// possible for canvases created while this was set. See `history.rs` for the
+    /// format of the log.
+    #[serde(default)]
+    pub record_history: bool,
+
+    /// Where archived canvases are moved to. If not set, this is `archive` in the
+    /// storage directory.
+    #[serde(default)]
+    pub archive_directory: Option<PathBuf>,
+}
+
+impl MmapPixelStorageConfig {
+    pub fn default_upgrade_legacy_files() -> bool {
+        true
+    }
+}
+
+const CANVAS_FILE_PREFIX: &str = "0x";
+const CANVAS_FILE_EXTENSION: &str = "canvas";
+const HISTORY_FILE_EXTENSION: &str = "history";
+
+/// How many images of each canvas we keep around, e.g. for the different renditions
+/// the flushers write. Past this we drop the image that was used least recently.
+const MAX_CACHED_IMAGES_PER_CANVAS: usize = 8;
+
+/// How many times we try to read a canvas file for a snapshot before giving up, in
+/// case we keep catching the header partway through an update.
+const SNAPSHOT_READ_ATTEMPTS: usize = 5;
+
+/// The files for a single canvas, e.g. for a snapshot of the storage directory.
+#[derive(Clone, Debug)]
+pub struct CanvasSnapshot {
+    pub canvas_address: Address,
+    /// The contents of the canvas file, see `format.rs`.
+    pub canvas_file: Vec<u8>,
+    /// The contents of the history file if there is one, see `history.rs`.
+    pub history_file: Option<Vec<u8>>,
+    /// Whether the canvas is archived, in which case it is restored into the archive
+    /// directory.
+    pub archived: bool,
+}
+
+/// Every pixel of a canvas as an index into its palette.
+#[derive(Clone, Debug)]
+pub struct CanvasPixels {
+    pub info: CanvasInfo,
+    /// The version of the last txn applied to the canvas, see `format.rs`.
+    pub last_applied_version: u64,
+    /// The palette index of each pixel, row by row from the top left.
+    pub pixels: Vec<u8>,
+}
+
+/// A pixel to overwrite when repairing a canvas.
+#[derive(Clone, Copy, Debug)]
+pub struct PixelRepair {
+    pub index: u32,
+    pub color: u8,
+}
+
+/// A canvas file we have mmapped, along with where everything lives in it.
+#[derive(Debug)]
+struct OpenCanvas {
+    mmap: MmapMut,
+    layout: CanvasLayout,
+    /// The history file for the canvas, if we're recording history for it.
+    history: Option<File>,
+    /// Goes up every time the pixels change, so we can tell whether a cached image
+    /// is still up to date.
+    generation: u64,
+    /// Images of the whole canvas we've encoded, keyed by how they were rendered.
+    image_cache: HashMap<(Scale, ImageFormat), CachedImage>,
+    /// The pixels drawn since we opened the canvas, see `changes.rs`.
+    recent_changes: RecentChanges,
+    /// Whether the canvas has been archived. Archived canvases are mapped read only
+    /// and never change.
+    archived: bool,
+}
+
+/// An encoded image of a canvas, along with the generation of the canvas it shows.
+#[derive(Debug)]
+struct CachedImage {
+    generation: u64,
+    data: Vec<u8>,
+    last_used: Instant,
+}
+
+impl OpenCanvas {
+    fn pixels(&self) -> &[u8] {
+        &self.mmap[self.layout.pixel_range()]
+    }
+
+    /// Every change to the pixels goes through here, so this is where we invalidate
+    /// cached images.
+    fn pixels_mut(&mut self) -> &mut [u8] {
+        self.generation += 1;
+        let range = self.layout.pixel_range();
+        &mut self.mmap[range]
+    }
+
+    fn info(&self, canvas_address: &Address) -> CanvasInfo {
+        CanvasInfo {
+            canvas_address: *canvas_address,
+            width: self.layout.header.width,
+            height: self.layout.header.height,
+            last_applied_version: self.layout.header.last_applied_version,
+            palette_id: self.layout.header.palette_id,
+            archived: self.archived,
+        }
+    }
+
+    /// Get a cached image of the canvas, if we have one of the current pixels.
+    fn cached_image(&mut self, scale: Scale, format: ImageFormat) -> Option<&[u8]> {
+        let image = self
+            .image_cache
+            .get_mut(&(scale, format))
+            .filter(|image| image.generation == self.generation)?;
+        image.last_used = Instant::now();
+        Some(image.data.as_slice())
+    }
+
+    /// Cache an image of the canvas as it was at the given generation.
+    fn cache_image(&mut self, scale: Scale, format: ImageFormat, generation: u64, data: Vec<u8>) {
+        // Images of older generations will never be used again.
+        let current = self.generation;
+        self.image_cache
+            .retain(|_, image| image.generation == current);
+        if generation != current {
+            return;
+        }
+        let key = (scale, format);
+        if self.image_cache.len() >= MAX_CACHED_IMAGES_PER_CANVAS
+            && !self.image_cache.contains_key(&key)
+        {
+            let least_recently_used = self
+                .image_cache
+                .iter()
+                .min_by_key(|(_, image)| image.last_used)
+                .map(|(key, _)| *key);
+            if let Some(least_recently_used) = least_recently_used {
+                self.image_cache.remove(&least_recently_used);
+            }
+        }
+        self.image_cache.insert(key, CachedImage {
+            generation,
+            data,
+            last_used: Instant::now(),
+        });
+    }
+
+    fn record_history(&mut self, records: &[HistoryRecord]) -> Result<()> {
+        match &mut self.history {
+            Some(history) => append_records(history, records),
+            None => Ok(()),
+        }
+    }
+
+    /// Record that every txn up to and including this version has been applied.
+    /// Legacy files have no header to store this in, so for them we only remember
+    /// it until the next restart.
+    fn set_last_applied_version(&mut self, version: u64) {
+        self.layout.header.last_applied_version = version;
+        if self.layout.format == CanvasFormat::Current {
+            let header = self.layout.header.to_bytes();
+            self.mmap[..HEADER_LEN].copy_from_slice(&header);
+        }
+    }
 }
 
 /// Handles creating, updating, and reading canvases.
 #[derive(Debug)]
 pub struct MmapPixelStorage {
     config: MmapPixelStorageConfig,
-    mmaps: Arc<Mutex<HashMap<Address, MmapMut>>>,
+    palettes: Arc<Palettes>,
+    /// Canvases that have been opened since startup. Canvas files that already exist
+    /// on disk are opened lazily the first time they're read or written.
+    canvases: Arc<Mutex<HashMap<Address, OpenCanvas>>>,
+    updates: CanvasUpdates,
 }
 
 impl MmapPixelStorage {
-    pub fn new(config: MmapPixelStorageConfig) -> Self {
-        Self {
+    /// Build the storage, validating any canvas files already in the storage
+    /// directory, e.g. from before a restart. If configured, this also upgrades any
+    /// legacy canvas files.
+    pub fn new(config: MmapPixelStorageConfig, palettes: Arc<Palettes>) -> Result<Self> {
+        if palettes.max_len() > config.pixel_encoding.max_index() as usize + 1 {
+            bail!(
+                "Pixel encoding {:?} can't store palettes with {} colors",
+                config.pixel_encoding,
+                palettes.max_len()
+            );
+        }
+
+        // Canvases are created lazily, so the directory may not exist yet.
+        std::fs::create_dir_all(&config.storage_directory).with_context(|| {
+            format!(
+                "Failed to create storage directory {}",
+                config.storage_directory.display()
+            )
+        })?;
+
+        let storage = Self {
             config,
-            mmaps: Arc::new(Mutex::new(HashMap::new())),
+            palettes,
+            canvases: Arc::new(Mutex::new(HashMap::new())),
+            updates: CanvasUpdates::new(),
+        };
+
+        let canvases = storage
+            .discover_canvases()
+            .context("Failed to discover existing canvases")?;
+        info!(
+            "Found {} existing canvases in {}",
+            canvases.len(),
+            storage.config.storage_directory.display()
+        );
+
+        if storage.config.upgrade_legacy_files {
+            for canvas in &canvases {
+                let filename = storage.get_filename(&canvas.canvas_address);
+                if upgrade_legacy_file(&filename)
+                    .with_context(|| format!("Failed to upgrade {}", filename.display()))?
+                {
+                    info!("Upgraded legacy canvas file {}", filename.display());
+                }
+            }
         }
+
+        Ok(storage)
     }
 
     fn get_filename(&self, canvas_address: &Address) -> PathBuf {
-        Path::new(&self.config.storage_directory)
-            .join(format!("0x{}.canvas", canvas_address.to_canonical_string()))
+        self.get_filename_with_extension(canvas_address, CANVAS_FILE_EXTENSION)
+    }
+
+    fn get_history_filename(&self, canvas_address: &Address) -> PathBuf {
+        self.get_filename_with_extension(canvas_address, HISTORY_FILE_EXTENSION)
+    }
+
+    /// Where the canvas file and history file are, depending on whether the canvas
+    /// is archived.
+    fn get_filenames(&self, canvas_address: &Address, archived: bool) -> (PathBuf, PathBuf) {
+        let filenames = (
+            self.get_filename(canvas_address),
+            self.get_history_filename(canvas_address),
+        );
+        if archived {
+            (
+                self.get_archived_filename(&filenames.0),
+                self.get_archived_filename(&filenames.1),
+            )
+        } else {
+            filenames
+        }
+    }
+
+    /// Where the history of the canvas is. Like the canvas file, it moves to the
+    /// archive directory when the canvas is archived.
+    fn get_current_history_filename(
+        &self,
+        canvas: &OpenCanvas,
+        canvas_address: &Address,
+    ) -> PathBuf {
+        self.get_filenames(canvas_address, canvas.archived).1
+    }
+
+    fn archive_directory(&self) -> PathBuf {
+        match &self.config.archive_directory {
+            Some(archive_directory) => archive_directory.clone(),
+            None => self.config.storage_directory.join("archive"),
+        }
+    }
+
+    /// Where the given file in the storage directory goes when it is archived.
+    fn get_archived_filename(&self, filename: &Path) -> PathBuf {
+        let name = filename.file_name().unwrap_or_default();
+        self.archive_directory().join(name)
+    }
+
+    fn get_filename_with_extension(&self, canvas_address: &Address, extension: &str) -> PathBuf {
+        Path::new(&self.config.storage_directory).join(format!(
+            "{}{}.{}",
+            CANVAS_FILE_PREFIX,
+            canvas_address.to_canonical_string(),
+            extension
+        ))
+    }
+
+    /// Open the canvas file for the given address and mmap it. If the canvas has been
+    /// archived we open it from the archive directory instead.
+    fn open_canvas(&self, canvas_address: &Address) -> PixelStorageResult<OpenCanvas> {
+        let mut filename = self.get_filename(canvas_address);
+        let archived = !filename.exists();
+        if archived {
+            filename = self.get_archived_filename(&filename);
+            if !filename.exists() {
+                return Err(PixelStorageError::CanvasNotFound {
+                    canvas_address: *canvas_address,
+                });
+            }
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!archived)
+            .create(false)
+            .open(&filename)
+            .with_context(|| format!("Failed to open file {}", filename.display()))?;
+        // Archived files are read only, so we map a private copy of them. We never
+        // write to archived canvases anyway.
+        let mmap = if archived {
+            unsafe { MmapOptions::new().map_copy(&file) }
+        } else {
+            unsafe { MmapMut::map_mut(&file) }
+        }
+        .with_context(|| format!("Failed to mmap file {}", filename.display()))?;
+        let layout = read_layout(&mmap).map_err(|e| PixelStorageError::CorruptCanvas {
+            canvas_address: *canvas_address,
+            source: e.context(format!("Invalid canvas file {}", filename.display())),
+        })?;
+
+        // If the canvas was created before we started recording history there is no
+        // history file, and there's no point starting one now since we couldn't
+        // replay it without knowing what the canvas looked like when it started.
+        let history_filename = self.get_history_filename(canvas_address);
+        let history = if self.config.record_history && !archived && history_filename.exists() {
+            Some(open_history_file(
+                &history_filename,
+                layout.header.last_applied_version,
+            )?)
+        } else {
+            None
+        };
+
+        // We only know about changes made after we open the canvas.
+        let recent_changes = RecentChanges::new(layout.header.last_applied_version);
+
+        Ok(OpenCanvas {
+            mmap,
+            layout,
+            history,
+            generation: 0,
+            image_cache: HashMap::new(),
+            recent_changes,
+            archived,
+        })
+    }
+
+    /// Get the canvas, opening the file on disk if we haven't already.
+    fn get_or_open_canvas<'a>(
+        &self,
+        canvases: &'a mut HashMap<Address, OpenCanvas>,
+        canvas_address: &Address,
+    ) -> PixelStorageResult<&'a mut OpenCanvas> {
+        Ok(match canvases.entry(*canvas_address) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.open_canvas(canvas_address)?),
+        })
+    }
+
+    /// Convert a pixel as stored in the file into an rgb color, looking up the color
+    /// in the canvas' palette if necessary.
+    fn to_rgb(&self, header: &CanvasHeader, color: StoredColor) -> Result<RgbColor> {
+        match color {
+            StoredColor::Rgb(color) => Ok(color),
+            StoredColor::Index(index) => self.palettes.color(header.palette_id, index).cloned(),
+        }
+    }
+
+    /// Convert a pixel as stored in the file into an index into the canvas' palette,
+    /// looking up the color in the palette if necessary.
+    fn to_index(&self, header: &CanvasHeader, color: StoredColor) -> Result<u8> {
+        match color {
+            StoredColor::Rgb(color) => self.palettes.index_of(header.palette_id, &color),
+            StoredColor::Index(index) => Ok(index),
+        }
+    }
+
+    /// Read every canvas in the storage directory, calling `f` on each. This is safe to
+    /// do while another process is writing to the canvases. The pixels we read might
+    /// include part of a batch after the last applied version in the header, but the
+    /// processor replays that whole batch after a restore, so the canvas ends up
+    /// right. We leave out history after the last applied version so it doesn't end
+    /// up in the history twice after the replay.
+    ///
+    /// This includes archived canvases.
+    pub fn snapshot_canvases(&self, mut f: impl FnMut(CanvasSnapshot) -> Result<()>) -> Result<()> {
+        let mut canvases = self.discover_canvases()?;
+        let archive_directory = self.archive_directory();
+        if archive_directory.exists() {
+            canvases.extend(discover_canvases_in(&archive_directory, true)?);
+        }
+        for canvas in canvases {
+            f(self.snapshot_canvas(&canvas.canvas_address, canvas.archived)?)?;
+        }
+        Ok(())
+    }
+
+    fn snapshot_canvas(&self, canvas_address: &Address, archived: bool) -> Result<CanvasSnapshot> {
+        let (filename, history_filename) = self.get_filenames(canvas_address, archived);
+        let mut attempt = 1;
+        let (canvas_file, layout) = loop {
+            let data = std::fs::read(&filename)
+                .with_context(|| format!("Failed to read {}", filename.display()))?;
+            // If the header was being updated as we read it the checksum won't match,
+            // so we try again.
+            match read_layout(&data) {
+                Ok(layout) => break (data, layout),
+                Err(e) if attempt < SNAPSHOT_READ_ATTEMPTS => {
+                    warn!("Failed to read {}, retrying: {:#}", filename.display(), e);
+                    attempt += 1;
+                },
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Invalid canvas file {}", filename.display()))
+                },
+            }
+        };
+
+        let history_file = if history_filename.exists() {
+            let data = std::fs::read(&history_filename)
+                .with_context(|| format!("Failed to read {}", history_filename.display()))?;
+            Some(trim_history(&data, layout.header.last_applied_version)?)
+        } else {
+            None
+        };
+
+        Ok(CanvasSnapshot {
+            canvas_address: *canvas_address,
+            canvas_file,
+            history_file,
+            archived,
+        })
+    }
+
+    /// Write a canvas from a snapshot into the storage directory, or the archive
+    /// directory if it is archived. This refuses to overwrite a canvas that already
+    /// exists.
+    pub fn restore_canvas(&self, snapshot: &CanvasSnapshot) -> Result<()> {
+        let canvas_address = &snapshot.canvas_address;
+        read_layout(&snapshot.canvas_file)
+            .with_context(|| format!("Invalid canvas file for {} in snapshot", canvas_address))?;
+        let active_filename = self.get_filename(canvas_address);
+        if active_filename.exists() || self.get_archived_filename(&active_filename).exists() {
+            bail!("Canvas {} already exists", canvas_address);
+        }
+        if snapshot.archived {
+            let archive_directory = self.archive_directory();
+            std::fs::create_dir_all(&archive_directory).with_context(|| {
+                format!(
+                    "Failed to create archive directory {}",
+                    archive_directory.display()
+                )
+            })?;
+        }
+        let (filename, history_filename) = self.get_filenames(canvas_address, snapshot.archived);
+
+        // Like when creating a canvas, we write the history first so the canvas file
+        // only exists once everything else is in place.
+        if let Some(history_file) = &snapshot.history_file {
+            std::fs::write(&history_filename, history_file)
+                .with_context(|| format!("Failed to write {}", history_filename.display()))?;
+            if snapshot.archived {
+                make_read_only(&history_filename)?;
+            }
+        }
+        let tmp_filename = filename.with_extension("canvas.tmp");
+        std::fs::write(&tmp_filename, &snapshot.canvas_file)
+            .with_context(|| format!("Failed to write {}", tmp_filename.display()))?;
+        if snapshot.archived {
+            make_read_only(&tmp_filename)?;
+        }
+        std::fs::rename(&tmp_filename, &filename)
+            .with_context(|| format!("Failed to move {} into place", tmp_filename.display()))?;
+        Ok(())
+    }
+
+    /// Read the palette index of every pixel in the canvas, row by row from the top
+    /// left, e.g. to check the canvas against the chain.
+    pub async fn read_canvas_pixels(
+        &self,
+        canvas_address: &Address,
+    ) -> PixelStorageResult<CanvasPixels> {
+        let mut canvases = self.canvases.lock().await;
+        let canvas = self.get_or_open_canvas(&mut canvases, canvas_address)?;
+        let header = &canvas.layout.header;
+        let pixels = (0..header.num_pixels())
+            .map(|pixel| {
+                let color = header.pixel_encoding.read(canvas.pixels(), pixel);
+                self.to_index(header, color)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(CanvasPixels {
+            info: canvas.info(canvas_address),
+            last_applied_version: header.last_applied_version,
+            pixels,
+        })
+    }
+
+    /// Overwrite pixels in the canvas outside of the normal processing flow, e.g. to
+    /// fix pixels that have drifted from the chain. This doesn't change the last
+    /// applied version and isn't recorded in the history, since the repair doesn't
+    /// correspond to any txn.
+    pub async fn repair_pixels(
+        &self,
+        canvas_address: &Address,
+        repairs: &[PixelRepair],
+    ) -> PixelStorageResult<()> {
+        let mut canvases = self.canvases.lock().await;
+        let canvas = self.get_or_open_canvas(&mut canvases, canvas_address)?;
+        if canvas.archived {
+            return Err(PixelStorageError::CanvasArchived {
+                canvas_address: *canvas_address,
+            });
+        }
+
+        // Check everything first so we don't apply half the repairs.
+        let header = &canvas.layout.header;
+        let colors = repairs
+            .iter()
+            .map(|repair| {
+                if repair.index as usize >= header.num_pixels() {
+                    return Err(PixelStorageError::OutOfBounds {
+                        canvas_address: *canvas_address,
+                        index: repair.index,
+                        width: header.width,
+                        height: header.height,
+                    });
+                }
+                self.palettes
+                    .color(header.palette_id, repair.color)
+                    .map_err(|_| PixelStorageError::InvalidColor {
+                        canvas_address: *canvas_address,
+                        color: repair.color,
+                    })
+            })
+            .collect::<PixelStorageResult<Vec<_>>>()?;
+
+        let encoding = header.pixel_encoding;
+        let pixels = canvas.pixels_mut();
+        for (repair, color) in repairs.iter().zip(colors) {
+            encoding.write(pixels, repair.index as usize, repair.color, color);
+        }
+        // Repairs don't have a version, so clients can't tell them apart from what
+        // they already have unless they start again.
+        self.updates
+            .publish(*canvas_address, CanvasChanges::FullImageRequired {
+                last_version: canvas.layout.header.last_applied_version,
+            });
+
+        info!(
+            "Repaired {} pixels in canvas {}",
+            repairs.len(),
+            canvas_address
+        );
+
+        Ok(())
+    }
+
+    /// Scan the storage directory for canvas files, not including archived canvases.
+    fn discover_canvases(&self) -> Result<Vec<CanvasInfo>> {
+        discover_canvases_in(&self.config.storage_directory, false)
     }
 }
 
+/// Archived files are read only, so nothing changes them by accident.
+fn make_read_only(path: &Path) -> Result<()> {
+    let mut permissions = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(path, permissions)
+        .with_context(|| format!("Failed to make {} read only", path.display()))
+}
+
+/// Scan the directory for canvas files, which are archived if this is the archive
+/// directory. Files that look like canvases but fail validation are logged and
+/// skipped.
+fn discover_canvases_in(directory: &Path, archived: bool) -> Result<Vec<CanvasInfo>> {
+    let entries = std::fs::read_dir(directory)
+        .with_context(|| format!("Failed to read directory {}", directory.display()))?;
+
+    let mut canvases = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let canvas_address = match parse_filename(&path) {
+            Some(canvas_address) => canvas_address,
+            None => continue,
+        };
+        let layout = File::open(&path)
+            .context("Failed to open file")
+            .and_then(|mut file| read_layout_from_file(&mut file));
+        match layout {
+            Ok(layout) => canvases.push(CanvasInfo {
+                canvas_address,
+                width: layout.header.width,
+                height: layout.header.height,
+                last_applied_version: layout.header.last_applied_version,
+                palette_id: layout.header.palette_id,
+                archived,
+            }),
+            Err(e) => warn!("Skipping canvas file {}: {:#}", path.display(), e),
+        }
+    }
+
+    Ok(canvases)
+}
+
 #[async_trait::async_trait]
 impl PixelStorageTrait for MmapPixelStorage {
-    /// Create a canvas as a file on disk. We use a custom format where each pixel is
-    /// stored as 3 bytes (r, g, b) and the width and height are stored at the end of
-    /// the file as 8 bytes each.
-    async fn create_canvas(&self, intent: CreateCanvasIntent) -> Result<()> {
+    /// Create a canvas as a file on disk. See `format.rs` for the layout of the file.
+    /// If the file already exists we leave it alone, see `check_existing_canvas`.
+    async fn create_canvas(&self, intent: CreateCanvasIntent) -> PixelStorageResult<()> {
         let filename = self.get_filename(&intent.canvas_address);
+        if filename.exists() || self.get_archived_filename(&filename).exists() {
+            let header = {
+                let mut canvases = self.canvases.lock().await;
+                let canvas = self.get_or_open_canvas(&mut canvases, &intent.canvas_address)?;
+                canvas.layout.header.clone()
+            };
+            return Ok(check_existing_canvas(&intent, header.last_applied_version)?);
+        }
+
         info!("Creating canvas file: {:?}", filename.display());
-        let mut file = File::create(&filename)?;
 
-        let width = intent.width as u64;
-        let height = intent.height as u64;
+        let mut header = CanvasHeader::new(
+            intent.width as u32,
+            intent.height as u32,
+            intent.default_color,
+            self.config.pixel_encoding,
+        );
+        header.palette_id = self.palettes.palette_id_for(&intent.canvas_address);
+        header.last_applied_version = intent.txn_version;
 
-        // Calculate the total number of pixels
-        let num_pixels = width * height;
+        let color = self
+            .palettes
+            .color(header.palette_id, intent.default_color)
+            .map_err(|_| PixelStorageError::InvalidColor {
+                canvas_address: intent.canvas_address,
+                color: intent.default_color,
+            })?;
 
-        // Build all the data into a single vector.
-        let mut data = Vec::with_capacity(num_pixels as usize * 3);
-        for _ in 0..num_pixels {
-            // We don't use the hardcoded colors at our level, we convert
-            // them into proper rgb colors.
-            let color = RgbColor::from(&intent.default_color);
-            data.push(color.r);
-            data.push(color.g);
-            data.push(color.b);
+        // We start the history before writing the canvas file. If we crash in
+        // between, the create is replayed and the history file is started again,
+        // whereas once the canvas file exists the create would be skipped.
+        if self.config.record_history {
+            let mut history =
+                create_history_file(&self.get_history_filename(&intent.canvas_address))?;
+            append_records(&mut history, &[HistoryRecord {
+                txn_version: intent.txn_version,
+                txn_timestamp_secs: intent.txn_timestamp_secs,
+                event: HistoryEvent::Create {
+                    default_color: intent.default_color,
+                },
+            }])?;
         }
 
-        // Put the width and height at the end of the file.
-        data.extend(width.to_le_bytes());
-        data.extend(height.to_le_bytes());
-
-        // Write the data to the file.
-        file.write_all(&data)?;
+        write_canvas_file(&filename, &header, color)?;
+        self.updates
+            .publish(intent.canvas_address, CanvasChanges::FullImageRequired {
+                last_version: intent.txn_version,
+            });
 
         info!("Created canvas file: {:?}", filename.display());
 
         Ok(())
     }
 
-    async fn write_pixels(&self, intents: Vec<WritePixelIntent>) -> Result<()> {
+    async fn write_pixels(&self, intents: Vec<WritePixelIntent>) -> PixelStorageResult<()> {
         // Create a map of canvas address to intents.
         let mut canvas_to_intents = HashMap::new();
         for intent in intents.into_iter() {
@@ -108,91 +751,399 @@ impl PixelStorageTrait for MmapPixelStorage {
         }
 
         for (canvas_address, intents) in canvas_to_intents.into_iter() {
-            let intents_len = intents.len();
             info!(
                 "Will write {} pixels to canvas {}",
-                intents_len, canvas_address,
+                intents.len(),
+                canvas_address,
             );
             // Get an existing mmap for the canvas file or initialize a new one.
-            let mut mmaps = self.mmaps.lock().await;
-            let mmap = mmaps.entry(canvas_address).or_insert_with(|| {
-                let filename = self.get_filename(&canvas_address);
-                let file = match OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(false)
-                    .open(&filename)
-                {
-                    Ok(file) => file,
-                    Err(e) => {
-                        error!("Failed to open file {}: {}", filename.display(), e);
-                        panic!("Failed to open file {}: {}", filename.display(), e);
-                    },
-                };
-                unsafe { MmapMut::map_mut(&file).expect("Failed to mmap file") }
-            });
+            let mut canvases = self.canvases.lock().await;
+            let canvas = self.get_or_open_canvas(&mut canvases, &canvas_address)?;
+
+            // Skip anything we applied before a restart, see `format.rs`.
+            let intents = skip_applied(
+                intents,
+                canvas.layout.header.last_applied_version,
+                |intent| intent.txn_version,
+            );
+            let last_version = match intents.iter().map(|intent| intent.txn_version).max() {
+                Some(version) => version,
+                None => {
+                    info!(
+                        "Pixels for canvas {} have already been written, skipping",
+                        canvas_address
+                    );
+                    continue;
+                },
+            };
+            if canvas.archived {
+                return Err(PixelStorageError::CanvasArchived { canvas_address });
+            }
+            let intents_len = intents.len();
             info!(
                 "Got mmap, will write {} pixels to canvas {}",
                 intents_len, canvas_address,
             );
 
+            // Check every pixel and look up all the colors first so we don't write
+            // half the pixels if one of them is invalid.
+            let header = &canvas.layout.header;
+            let colors = intents
+                .iter()
+                .map(|intent| {
+                    check_in_bounds(intent, header.width, header.height)?;
+                    self.palettes
+                        .color(header.palette_id, intent.color)
+                        .map_err(|_| PixelStorageError::InvalidColor {
+                            canvas_address,
+                            color: intent.color,
+                        })
+                })
+                .collect::<PixelStorageResult<Vec<_>>>()?;
+
             // Write the pixels to the file through the mmap.
-            for intent in intents {
-                // We don't use the hardcoded colors at our level, we convert
-                // them into proper rgb colors.
-                let index = intent.index as usize;
-                let color = RgbColor::from(&intent.color);
-                mmap[index * 3] = color.r;
-                mmap[index * 3 + 1] = color.g;
-                mmap[index * 3 + 2] = color.b;
+            let encoding = header.pixel_encoding;
+            let pixels = canvas.pixels_mut();
+            for (intent, color) in intents.iter().zip(colors) {
+                // Depending on the encoding we either store the index of the color
+                // or the actual rgb color.
+                encoding.write(pixels, intent.index as usize, intent.color, color);
             }
 
+            let changes = intents
+                .iter()
+                .map(|intent| PixelChange {
+                    index: intent.index,
+                    color: intent.color,
+                    version: intent.txn_version,
+                })
+                .collect::<Vec<_>>();
+            canvas.recent_changes.record(changes.iter().copied());
+            let records = intents
+                .into_iter()
+                .map(|intent| HistoryRecord {
+                    txn_version: intent.txn_version,
+                    txn_timestamp_secs: intent.txn_timestamp_secs,
+                    event: HistoryEvent::Draw {
+                        index: intent.index,
+                        color: intent.color,
+                        artist_address: intent.artist_address,
+                    },
+                })
+                .collect::<Vec<_>>();
+            // We append to the history before updating the last applied version, so
+            // if we crash in between the records are cut off when the canvas is next
+            // opened and appended again when the txns are replayed, see
+            // `open_history_file`. The other way around we'd lose them.
+            canvas
+                .record_history(&records)
+                .with_context(|| format!("Failed to record history for {}", canvas_address))?;
+            canvas.set_last_applied_version(last_version);
+            self.updates
+                .publish(canvas_address, CanvasChanges::Changes {
+                    last_version,
+                    changes,
+                });
+
             info!("Wrote {} pixels to canvas {}", intents_len, canvas_address,);
         }
 
         Ok(())
     }
 
+    /// Overwrite every pixel in the canvas with the default color.
+    async fn clear_canvas(&self, intent: ClearCanvasIntent) -> PixelStorageResult<()> {
+        let canvas_address = intent.canvas_address;
+        info!("Clearing canvas {}", canvas_address);
+
+        let mut canvases = self.canvases.lock().await;
+        let canvas = self.get_or_open_canvas(&mut canvases, &canvas_address)?;
+
+        let header = &canvas.layout.header;
+        if intent.txn_version <= header.last_applied_version {
+            info!(
+                "Clear of canvas {} at version {} has already been applied, skipping",
+                canvas_address, intent.txn_version
+            );
+            return Ok(());
+        }
+        if canvas.archived {
+            return Err(PixelStorageError::CanvasArchived { canvas_address });
+        }
+        let color = self
+            .palettes
+            .color(header.palette_id, intent.default_color)
+            .map_err(|_| PixelStorageError::InvalidColor {
+                canvas_address,
+                color: intent.default_color,
+            })?;
+        let encoding = header.pixel_encoding;
+        encoding.fill(canvas.pixels_mut(), intent.default_color, color);
+
+        // The history goes before the last applied version, as in `write_pixels`.
+        canvas
+            .record_history(&[HistoryRecord {
+                txn_version: intent.txn_version,
+                txn_timestamp_secs: intent.txn_timestamp_secs,
+                event: HistoryEvent::Clear {
+                    default_color: intent.default_color,
+                },
+            }])
+            .with_context(|| format!("Failed to record history for {}", canvas_address))?;
+        canvas.recent_changes.reset(intent.txn_version);
+        canvas.set_last_applied_version(intent.txn_version);
+        self.updates
+            .publish(canvas_address, CanvasChanges::FullImageRequired {
+                last_version: intent.txn_version,
+            });
+
+        info!("Cleared canvas {}", canvas_address);
+
+        Ok(())
+    }
+
+    /// Move the canvas file (and history file, if there is one) to the archive
+    /// directory and make them read only. We move the history before the canvas
+    /// file, so if we crash partway through, the canvas is still active and the
+    /// archive is applied again on replay.
+    async fn archive_canvas(&self, intent: ArchiveCanvasIntent) -> PixelStorageResult<()> {
+        let canvas_address = intent.canvas_address;
+        let mut canvases = self.canvases.lock().await;
+        let canvas = self.get_or_open_canvas(&mut canvases, &canvas_address)?;
+        if canvas.archived {
+            info!("Canvas {} is already archived, skipping", canvas_address);
+            return Ok(());
+        }
+        info!("Archiving canvas {}", canvas_address);
+
+        if intent.txn_version > canvas.layout.header.last_applied_version {
+            canvas.set_last_applied_version(intent.txn_version);
+        }
+        canvas
+            .mmap
+            .flush()
+            .with_context(|| format!("Failed to flush canvas {}", canvas_address))?;
+        // Drop the mmap (and history file) before moving the files around.
+        canvases.remove(&canvas_address);
+
+        let archive_directory = self.archive_directory();
+        std::fs::create_dir_all(&archive_directory).with_context(|| {
+            format!(
+                "Failed to create archive directory {}",
+                archive_directory.display()
+            )
+        })?;
+        for filename in [
+            self.get_history_filename(&canvas_address),
+            self.get_filename(&canvas_address),
+        ] {
+            if !filename.exists() {
+                continue;
+            }
+            let archived_filename = self.get_archived_filename(&filename);
+            std::fs::rename(&filename, &archived_filename).with_context(|| {
+                format!(
+                    "Failed to move {} to {}",
+                    filename.display(),
+                    archived_filename.display()
+                )
+            })?;
+            make_read_only(&archived_filename)?;
+        }
+        // Open the canvas again from the archive, so it is still flushed and served.
+        self.get_or_open_canvas(&mut canvases, &canvas_address)?;
+
+        info!("Archived canvas {}", canvas_address);
+
+        Ok(())
+    }
+
+    async fn delete_canvas(&self, canvas_address: &Address) -> PixelStorageResult<()> {
+        let mut canvases = self.canvases.lock().await;
+        canvases.remove(canvas_address);
+
+        let mut deleted = false;
+        for filename in [
+            self.get_filename(canvas_address),
+            self.get_history_filename(canvas_address),
+        ] {
+            for filename in [self.get_archived_filename(&filename), filename] {
+                match std::fs::remove_file(&filename) {
+                    Ok(()) => deleted = true,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                    Err(e) => {
+                        return Err(anyhow::Error::new(e)
+                            .context(format!("Failed to delete {}", filename.display()))
+                            .into())
+                    },
+                }
+            }
+        }
+        if !deleted {
+            return Err(PixelStorageError::CanvasNotFound {
+                canvas_address: *canvas_address,
+            });
+        }
+
+        info!("Deleted canvas {}", canvas_address);
+
+        Ok(())
+    }
+
     async fn get_canvas_as_png(&self, canvas_address: &Address) -> Result<Vec<u8>> {
-        let (data, width, height) = {
-            let mmaps = self.mmaps.lock().await;
-            let mmap = mmaps.get(canvas_address).context("Failed to find canvas")?;
-
-            // Get the width and height from the end of the file.
-            let (width, height) =
-                read_width_and_height(mmap).context("Failed to read width and height")?;
-
-            // Read the data from the file as a vector of RgbColors.
-            let mut data = Vec::with_capacity((width * height) as usize);
-            for i in 0..width * height {
-                let index = i as usize;
-                data.push(RgbColor {
-                    r: mmap[index * 3],
-                    g: mmap[index * 3 + 1],
-                    b: mmap[index * 3 + 2],
-                });
+        self.get_scaled_canvas_as_image(canvas_address, Scale::Up(1), ImageFormat::Png)
+            .await
+    }
+
+    /// Images of the whole canvas are cached until the canvas next changes, so
+    /// reading a canvas nobody is drawing on, e.g. from the flushers, is cheap.
+    async fn get_scaled_canvas_as_image(
+        &self,
+        canvas_address: &Address,
+        scale: Scale,
+        format: ImageFormat,
+    ) -> Result<Vec<u8>> {
+        let (info, generation) = {
+            let mut canvases = self.canvases.lock().await;
+            let canvas = self
+                .get_or_open_canvas(&mut canvases, canvas_address)
+                .context("Failed to find canvas")?;
+            if let Some(image) = canvas.cached_image(scale, format) {
+                return Ok(image.to_vec());
             }
-            (data, width, height)
+            (canvas.info(canvas_address), canvas.generation)
+        };
+
+        // If the canvas changes while we're encoding, the image is cached against
+        // the old generation and so is never used.
+        let image = self
+            .get_canvas_region_as_image(canvas_address, Region::full(&info), scale, format)
+            .await?;
+        if let Some(canvas) = self.canvases.lock().await.get_mut(canvas_address) {
+            canvas.cache_image(scale, format, generation, image.clone());
+        }
+
+        Ok(image)
+    }
+
+    async fn get_canvas_region_as_image(
+        &self,
+        canvas_address: &Address,
+        region: Region,
+        scale: Scale,
+        format: ImageFormat,
+    ) -> Result<Vec<u8>> {
+        // Raw images are made of palette indices rather than colors.
+        if format == ImageFormat::Raw {
+            let indices = {
+                let mut canvases = self.canvases.lock().await;
+                let canvas = self
+                    .get_or_open_canvas(&mut canvases, canvas_address)
+                    .context("Failed to find canvas")?;
+                read_region(canvas, region, |header, color| self.to_index(header, color))?
+            };
+            let (indices, width, height) =
+                scale_indices(indices, region.width, region.height, scale)?;
+            return Ok(encode_raw(&indices, width, height));
+        }
+
+        let data = {
+            let mut canvases = self.canvases.lock().await;
+            let canvas = self
+                .get_or_open_canvas(&mut canvases, canvas_address)
+                .context("Failed to find canvas")?;
+            read_region(canvas, region, |header, color| self.to_rgb(header, color))?
         };
 
-        // Convert the data to a png.
-        let png = get_image(data, width as u32, height as u32)
+        let (data, width, height) = scale_image(data, region.width, region.height, scale)?;
+
+        // Convert the data to an image.
+        let image = encode_image(data, width, height, format)
+            .with_context(|| format!("Failed to convert data to {:?}", format))?;
+
+        Ok(image)
+    }
+
+    /// Replay the history file for the canvas. We only need the lock to look up the
+    /// header, the history file is only ever appended to, so we can read it while
+    /// more pixels are being written.
+    async fn get_canvas_as_png_at(
+        &self,
+        canvas_address: &Address,
+        point: PointInTime,
+    ) -> Result<Vec<u8>> {
+        let (header, history_filename) = {
+            let mut canvases = self.canvases.lock().await;
+            let canvas = self
+                .get_or_open_canvas(&mut canvases, canvas_address)
+                .context("Failed to find canvas")?;
+            (
+                canvas.layout.header.clone(),
+                self.get_current_history_filename(canvas, canvas_address),
+            )
+        };
+
+        if !history_filename.exists() {
+            bail!("No history has been recorded for canvas {}", canvas_address);
+        }
+        let indices = replay(
+            HistorySource::File(&history_filename),
+            header.num_pixels(),
+            point,
+        )
+        .with_context(|| format!("Failed to replay history for {}", canvas_address))?;
+
+        let data = indices
+            .into_iter()
+            .map(|index| self.palettes.color(header.palette_id, index).cloned())
+            .collect::<Result<Vec<_>>>()?;
+        let png = get_image(data, header.width, header.height)
             .context("Failed to convert data to a png")?;
 
         Ok(png)
     }
 
+    async fn get_canvas_timelapse(
+        &self,
+        canvas_address: &Address,
+        options: &TimelapseOptions,
+    ) -> Result<Timelapse> {
+        let (header, history_filename) = {
+            let mut canvases = self.canvases.lock().await;
+            let canvas = self
+                .get_or_open_canvas(&mut canvases, canvas_address)
+                .context("Failed to find canvas")?;
+            (
+                canvas.layout.header.clone(),
+                self.get_current_history_filename(canvas, canvas_address),
+            )
+        };
+
+        if !history_filename.exists() {
+            bail!("No history has been recorded for canvas {}", canvas_address);
+        }
+        let palette = self.palettes.colors(header.palette_id)?;
+        render_timelapse(
+            HistorySource::File(&history_filename),
+            header.width,
+            header.height,
+            palette,
+            options,
+        )
+        .with_context(|| format!("Failed to render timelapse for {}", canvas_address))
+    }
+
     /// This function returns all the canvases in the mmap as pngs. We use this for the
     /// flusher, which takes the local mmap data and writes it to an external location
-    /// as PNGs. One thing worth noting is `mmaps` doesn't contain every file on disk,
-    /// only those written to at least once since startup. This is actually fine for
-    /// use in the flusher, since if nothing has been written since startup, there is
-    /// also nothing to flush.
+    /// as PNGs. One thing worth noting is `canvases` doesn't contain every file on
+    /// disk, only those read or written at least once since startup. This is actually
+    /// fine for use in the flusher, since if nothing has been written since startup,
+    /// there is also nothing to flush.
     async fn get_canvases_as_pngs(&self) -> Result<HashMap<Address, Vec<u8>>> {
         let mut pngs = HashMap::new();
         let addresses = {
-            let mmaps = self.mmaps.lock().await;
-            mmaps.iter().map(|mmap| *mmap.0).collect::<Vec<_>>()
+            let canvases = self.canvases.lock().await;
+            canvases.keys().copied().collect::<Vec<_>>()
         };
         for address in addresses {
             let png = self
@@ -203,24 +1154,101 @@ impl PixelStorageTrait for MmapPixelStorage {
         }
         Ok(pngs)
     }
-}
 
-/// Read the width and height from the end of the file.
-fn read_width_and_height(mmap: &MmapMut) -> Result<(u64, u64)> {
-    let len = mmap.len();
-    if len < 16 {
-        anyhow::bail!("File is too short to contain width and height");
+    /// This includes archived canvases.
+    async fn list_canvases(&self) -> Result<Vec<CanvasInfo>> {
+        let mut canvases = self.discover_canvases()?;
+        let archive_directory = self.archive_directory();
+        if archive_directory.exists() {
+            canvases.extend(discover_canvases_in(&archive_directory, true)?);
+        }
+        Ok(canvases)
+    }
+
+    async fn get_canvas_info(&self, canvas_address: &Address) -> Result<CanvasInfo> {
+        let mut canvases = self.canvases.lock().await;
+        let canvas = self
+            .get_or_open_canvas(&mut canvases, canvas_address)
+            .context("Failed to find canvas")?;
+        Ok(canvas.info(canvas_address))
+    }
+
+    /// We look at the changes in memory first, then fall back to the history file.
+    /// Like when replaying, we don't need the lock to read the history file.
+    async fn get_canvas_changes(
+        &self,
+        canvas_address: &Address,
+        since_version: u64,
+    ) -> Result<CanvasChanges> {
+        let (last_version, history_filename) = {
+            let mut canvases = self.canvases.lock().await;
+            let canvas = self
+                .get_or_open_canvas(&mut canvases, canvas_address)
+                .context("Failed to find canvas")?;
+            let last_version = canvas.layout.header.last_applied_version;
+            if let Some(changes) = canvas.recent_changes.since(since_version, last_version) {
+                return Ok(changes);
+            }
+            (
+                last_version,
+                self.get_current_history_filename(canvas, canvas_address),
+            )
+        };
+
+        if !history_filename.exists() {
+            return Ok(CanvasChanges::FullImageRequired { last_version });
+        }
+        changes_from_history(
+            HistorySource::File(&history_filename),
+            since_version,
+            last_version,
+        )
+        .with_context(|| format!("Failed to read changes for {}", canvas_address))
+    }
+
+    fn subscribe_to_updates(&self) -> broadcast::Receiver<Arc<CanvasUpdate>> {
+        self.updates.subscribe()
     }
+}
 
-    let width_bytes: [u8; 8] = mmap[len - 16..len - 8]
-        .try_into()
-        .context("Failed to read width")?;
-    let height_bytes: [u8; 8] = mmap[len - 8..len]
-        .try_into()
-        .context("Failed to read height")?;
+/// Read the pixels in the region of the canvas row by row, converting each with the
+/// given function.
+fn read_region<T>(
+    canvas: &OpenCanvas,
+    region: Region,
+    convert: impl Fn(&CanvasHeader, StoredColor) -> Result<T>,
+) -> Result<Vec<T>> {
+    let header = &canvas.layout.header;
+    if !region.fits_within(header.width, header.height) {
+        bail!(
+            "Region {:?} is not within the {}x{} canvas",
+            region,
+            header.width,
+            header.height
+        );
+    }
 
-    let width = u64::from_le_bytes(width_bytes);
-    let height = u64::from_le_bytes(height_bytes);
+    let pixels = canvas.pixels();
+    let mut data = Vec::with_capacity(region.width as usize * region.height as usize);
+    for y in region.y..region.y + region.height {
+        let row_start = y as usize * header.width as usize;
+        for x in region.x..region.x + region.width {
+            let color = header.pixel_encoding.read(pixels, row_start + x as usize);
+            data.push(convert(header, color)?);
+        }
+    }
+    Ok(data)
+}
 
-    Ok((width, height))
+/// Get the canvas address from a filename like `0x<address>.canvas`. If the filename
+/// doesn't look like that this returns None.
+fn parse_filename(path: &Path) -> Option<Address> {
+    if path.extension()? != CANVAS_FILE_EXTENSION {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    if !stem.starts_with(CANVAS_FILE_PREFIX) {
+        return None;
+    }
+    Address::from_str(stem).ok()
 }
@@ -0,0 +1,349 @@
+// This is the alternative to the mmap storage described at the top of `mmap.rs`.
+// Rather than files we keep open, each canvas is an image in an object store, which
+// we update by writing the whole image again after every change. This means the
+// processor doesn't need a persistent disk, at the cost of encoding and uploading
+// an image for every batch of pixels.
+//
+// Each canvas is stored as `0x<address>.png`, an indexed PNG using the canvas'
+// palette. This way the pixels are exactly the palette indices from the contract,
+// but the object is still a normal image anyone can look at. We also store the
+// palette ID, the version of the last txn applied to the canvas, and whether the
+// canvas is archived in tEXt chunks, so writing a canvas is a single put.
+//
+// We keep every canvas in memory, loading them all from the object store at startup.
+//
+// We don't keep the history of canvases, so unlike mmap storage this can't render a
+// canvas as it was at some point in the past or render a timelapse.
+
+use super::{
+    changes::{CanvasChanges, CanvasUpdate},
+    memory::{CanvasState, MemoryPixelStorage, MemoryPixelStorageConfig},
+    object_store::{ObjectStore, ObjectStoreConfig},
+    ArchiveCanvasIntent, CanvasInfo, ClearCanvasIntent, CreateCanvasIntent, PixelStorageResult,
+    PixelStorageTrait, WritePixelIntent,
+};
+use crate::{
+    ImageFormat, Palettes, PointInTime, Region, RgbColor, Scale, Timelapse, TimelapseOptions,
+};
+use anyhow::{bail, Context, Result};
+use aptos_move_graphql_scalars::Address;
+use png::{BitDepth, ColorType, Decoder, Encoder, Transformations};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::{broadcast, Mutex};
+use tracing::{info, warn};
+
+const CANVAS_KEY_PREFIX: &str = "0x";
+const CANVAS_KEY_EXTENSION: &str = ".png";
+const PALETTE_ID_KEYWORD: &str = "graffio:palette_id";
+const LAST_APPLIED_VERSION_KEYWORD: &str = "graffio:last_applied_version";
+const ARCHIVED_KEYWORD: &str = "graffio:archived";
+
+/// Each change rewrites the whole image of the canvas in the object store, and no
+/// history is kept, so images of canvases in the past and timelapses are
+/// unsupported.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectPixelStorageConfig {
+    /// Where to keep the canvases.
+    pub object_store: ObjectStoreConfig,
+}
+
+/// Handles creating, updating, and reading canvases kept in an object store.
+#[derive(Debug)]
+pub struct ObjectPixelStorage {
+    store: Arc<dyn ObjectStore>,
+    palettes: Arc<Palettes>,
+    /// The canvases as they are in the object store, or about to be.
+    canvases: MemoryPixelStorage,
+    /// Held while changing a canvas and writing it to the object store, so an older
+    /// version of a canvas never replaces a newer one.
+    write_lock: Mutex<()>,
+}
+
+impl ObjectPixelStorage {
+    /// Build the storage, loading every canvas from the object store.
+    pub async fn new(config: ObjectPixelStorageConfig, palettes: Arc<Palettes>) -> Result<Self> {
+        let store = config
+            .object_store
+            .build()
+            .context("Failed to build object store")?;
+        let storage = Self {
+            store,
+            palettes: palettes.clone(),
+            canvases: MemoryPixelStorage::new(MemoryPixelStorageConfig::default(), palettes),
+            write_lock: Mutex::new(()),
+        };
+
+        let keys = storage
+            .store
+            .list()
+            .await
+            .context("Failed to list objects")?;
+        let mut num_canvases = 0;
+        for key in keys {
+            let canvas_address = match parse_key(&key) {
+                Some(canvas_address) => canvas_address,
+                None => continue,
+            };
+            let data = match storage.store.get(&key).await? {
+                Some(data) => data,
+                None => {
+                    warn!("Canvas {} disappeared while loading, skipping", key);
+                    continue;
+                },
+            };
+            let state = decode_canvas(&data)
+                .with_context(|| format!("Failed to load canvas {}", canvas_address))?;
+            storage
+                .palettes
+                .colors(state.palette_id)
+                .with_context(|| format!("Failed to load canvas {}", canvas_address))?;
+            storage.canvases.insert_canvas(canvas_address, state).await;
+            num_canvases += 1;
+        }
+        info!("Loaded {} canvases from the object store", num_canvases);
+
+        Ok(storage)
+    }
+
+    /// Write the canvases as they are in memory to the object store.
+    async fn persist(&self, canvas_addresses: impl IntoIterator<Item = Address>) -> Result<()> {
+        for canvas_address in canvas_addresses {
+            let state = self.canvases.canvas_state(&canvas_address).await?;
+            let palette = self.palettes.colors(state.palette_id)?;
+            let data = encode_canvas(&state, palette)
+                .with_context(|| format!("Failed to encode canvas {}", canvas_address))?;
+            self.store
+                .put(&canvas_key(&canvas_address), data)
+                .await
+                .with_context(|| format!("Failed to write canvas {}", canvas_address))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl PixelStorageTrait for ObjectPixelStorage {
+    /// If writing to the object store fails after the canvas was updated in memory,
+    /// the processor retries the txn. The change is skipped in memory since it has
+    /// already been applied, but we still write the canvas to the object store.
+    async fn create_canvas(&self, intent: CreateCanvasIntent) -> PixelStorageResult<()> {
+        let _write_lock = self.write_lock.lock().await;
+        let canvas_address = intent.canvas_address;
+        self.canvases.create_canvas(intent).await?;
+        self.persist([canvas_address]).await?;
+        Ok(())
+    }
+
+    async fn write_pixels(&self, intents: Vec<WritePixelIntent>) -> PixelStorageResult<()> {
+        let _write_lock = self.write_lock.lock().await;
+        let canvas_addresses = intents
+            .iter()
+            .map(|intent| intent.canvas_address)
+            .collect::<HashSet<_>>();
+
+        // If this fails the pixels for some canvases might have been written, so we
+        // write everything that exists regardless.
+        let result = self.canvases.write_pixels(intents).await;
+        let mut existing = Vec::new();
+        for canvas_address in canvas_addresses {
+            if self.canvases.canvas_state(&canvas_address).await.is_ok() {
+                existing.push(canvas_address);
+            }
+        }
+        self.persist(existing).await?;
+        result
+    }
+
+    async fn clear_canvas(&self, intent: ClearCanvasIntent) -> PixelStorageResult<()> {
+        let _write_lock = self.write_lock.lock().await;
+        let canvas_address = intent.canvas_address;
+        self.canvases.clear_canvas(intent).await?;
+        self.persist([canvas_address]).await?;
+        Ok(())
+    }
+
+    /// Archived canvases stay in the object store, marked as archived.
+    async fn archive_canvas(&self, intent: ArchiveCanvasIntent) -> PixelStorageResult<()> {
+        let _write_lock = self.write_lock.lock().await;
+        let canvas_address = intent.canvas_address;
+        self.canvases.archive_canvas(intent).await?;
+        self.persist([canvas_address]).await?;
+        Ok(())
+    }
+
+    /// We delete the object before forgetting the canvas, so if the delete fails the
+    /// canvas is still there to delete again.
+    async fn delete_canvas(&self, canvas_address: &Address) -> PixelStorageResult<()> {
+        let _write_lock = self.write_lock.lock().await;
+        self.canvases.canvas_state(canvas_address).await?;
+        self.store
+            .delete(&canvas_key(canvas_address))
+            .await
+            .with_context(|| format!("Failed to delete canvas {}", canvas_address))?;
+        self.canvases.delete_canvas(canvas_address).await
+    }
+
+    async fn get_canvas_as_png(&self, canvas_address: &Address) -> Result<Vec<u8>> {
+        self.canvases.get_canvas_as_png(canvas_address).await
+    }
+
+    async fn get_canvas_region_as_image(
+        &self,
+        canvas_address: &Address,
+        region: Region,
+        scale: Scale,
+        format: ImageFormat,
+    ) -> Result<Vec<u8>> {
+        self.canvases
+            .get_canvas_region_as_image(canvas_address, region, scale, format)
+            .await
+    }
+
+    /// We don't keep history in the object store, so there is nothing to replay.
+    async fn get_canvas_as_png_at(
+        &self,
+        canvas_address: &Address,
+        _point: PointInTime,
+    ) -> Result<Vec<u8>> {
+        bail!(
+            "Can't get canvas {} at a point in time, object pixel storage doesn't keep history",
+            canvas_address
+        )
+    }
+
+    async fn get_canvas_timelapse(
+        &self,
+        canvas_address: &Address,
+        _options: &TimelapseOptions,
+    ) -> Result<Timelapse> {
+        bail!(
+            "Can't render a timelapse of canvas {}, object pixel storage doesn't keep history",
+            canvas_address
+        )
+    }
+
+    async fn get_canvases_as_pngs(&self) -> Result<HashMap<Address, Vec<u8>>> {
+        self.canvases.get_canvases_as_pngs().await
+    }
+
+    async fn list_canvases(&self) -> Result<Vec<CanvasInfo>> {
+        self.canvases.list_canvases().await
+    }
+
+    async fn get_canvas_info(&self, canvas_address: &Address) -> Result<CanvasInfo> {
+        self.canvases.get_canvas_info(canvas_address).await
+    }
+
+    async fn get_canvas_changes(
+        &self,
+        canvas_address: &Address,
+        since_version: u64,
+    ) -> Result<CanvasChanges> {
+        self.canvases
+            .get_canvas_changes(canvas_address, since_version)
+            .await
+    }
+
+    /// Updates are published once they're applied in memory, which is just before
+    /// they're written to the object store.
+    fn subscribe_to_updates(&self) -> broadcast::Receiver<Arc<CanvasUpdate>> {
+        self.canvases.subscribe_to_updates()
+    }
+}
+
+fn canvas_key(canvas_address: &Address) -> String {
+    format!(
+        "{}{}{}",
+        CANVAS_KEY_PREFIX,
+        canvas_address.to_canonical_string(),
+        CANVAS_KEY_EXTENSION
+    )
+}
+
+/// Get the canvas address from a key like `0x<address>.png`. If the key doesn't look
+/// like that this returns None.
+fn parse_key(key: &str) -> Option<Address> {
+    let stem = key.strip_suffix(CANVAS_KEY_EXTENSION)?;
+    if !stem.starts_with(CANVAS_KEY_PREFIX) {
+        return None;
+    }
+    Address::from_str(stem).ok()
+}
+
+/// Encode the canvas as an indexed PNG, see the top of this file.
+fn encode_canvas(state: &CanvasState, palette: &[RgbColor]) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut encoder = Encoder::new(&mut buffer, state.width, state.height);
+    encoder.set_color(ColorType::Indexed);
+    encoder.set_depth(BitDepth::Eight);
+    encoder.set_palette(
+        palette
+            .iter()
+            .flat_map(|color| [color.r, color.g, color.b])
+            .collect::<Vec<_>>(),
+    );
+    encoder.add_text_chunk(PALETTE_ID_KEYWORD.to_string(), state.palette_id.to_string())?;
+    encoder.add_text_chunk(
+        LAST_APPLIED_VERSION_KEYWORD.to_string(),
+        state.last_applied_version.to_string(),
+    )?;
+    if state.archived {
+        encoder.add_text_chunk(ARCHIVED_KEYWORD.to_string(), true.to_string())?;
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&state.pixels)?;
+    writer.finish()?;
+    Ok(buffer)
+}
+
+/// Decode a canvas written by `encode_canvas`.
+fn decode_canvas(data: &[u8]) -> Result<CanvasState> {
+    let mut decoder = Decoder::new(data);
+    // We want the palette indices rather than the colors they map to.
+    decoder.set_transformations(Transformations::IDENTITY);
+    let mut reader = decoder.read_info()?;
+    let info = reader.info();
+    if (info.color_type, info.bit_depth) != (ColorType::Indexed, BitDepth::Eight) {
+        bail!(
+            "Expected an 8 bit indexed PNG, got {:?} at {:?}",
+            info.color_type,
+            info.bit_depth
+        );
+    }
+    let text = |keyword: &str| {
+        info.uncompressed_latin1_text
+            .iter()
+            .find(|chunk| chunk.keyword == keyword)
+            .map(|chunk| chunk.text.clone())
+            .with_context(|| format!("Missing {} text chunk", keyword))
+    };
+    let palette_id = text(PALETTE_ID_KEYWORD)?
+        .parse()
+        .context("Invalid palette ID")?;
+    let last_applied_version = text(LAST_APPLIED_VERSION_KEYWORD)?
+        .parse()
+        .context("Invalid last applied version")?;
+    // Only archived canvases have this chunk.
+    let archived = match text(ARCHIVED_KEYWORD) {
+        Ok(archived) => archived.parse().context("Invalid archived flag")?,
+        Err(_) => false,
+    };
+
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut pixels)?;
+    pixels.truncate(frame.buffer_size());
+    Ok(CanvasState {
+        width: frame.width,
+        height: frame.height,
+        palette_id,
+        last_applied_version,
+        pixels,
+        archived,
+    })
+}
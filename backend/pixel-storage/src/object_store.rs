@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Debug, path::PathBuf, sync::Arc};
+
+/// A flat store of objects keyed by name, e.g. a directory or a bucket.
+#[async_trait::async_trait]
+pub trait ObjectStore: Debug + Send + Sync + 'static {
+    /// Get the object with the given key, or None if there isn't one.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    /// Create or replace the object with the given key. Readers see either the old
+    /// or the new object, never part of one.
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+    /// Delete the object with the given key. Deleting an object that doesn't exist
+    /// is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+    /// List the keys of every object in the store.
+    async fn list(&self) -> Result<Vec<String>>;
+}
+
+/// Which object store to use, picked with `type`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ObjectStoreConfig {
+    /// Store each object as a file in a directory.
+    Local(LocalObjectStoreConfig),
+}
+
+impl ObjectStoreConfig {
+    pub fn build(&self) -> Result<Arc<dyn ObjectStore>> {
+        Ok(match self {
+            ObjectStoreConfig::Local(config) => Arc::new(LocalObjectStore::new(config.clone())?),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalObjectStoreConfig {
+    pub directory: PathBuf,
+}
+
+/// An object store backed by a directory on the local filesystem.
+#[derive(Debug)]
+pub struct LocalObjectStore {
+    config: LocalObjectStoreConfig,
+}
+
+impl LocalObjectStore {
+    pub fn new(config: LocalObjectStoreConfig) -> Result<Self> {
+        std::fs::create_dir_all(&config.directory).with_context(|| {
+            format!(
+                "Failed to create object store directory {}",
+                config.directory.display()
+            )
+        })?;
+        Ok(Self { config })
+    }
+}
+
+#[async_trait::async_trait]
+impl ObjectStore for LocalObjectStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.config.directory.join(key);
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// We write to a temporary file and rename it into place, so the put is atomic.
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.config.directory.join(key);
+        let tmp_path = self.config.directory.join(format!("{}.tmp", key));
+        tokio::fs::write(&tmp_path, data)
+            .await
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        tokio::fs::rename(&tmp_path, &path)
+            .await
+            .with_context(|| format!("Failed to move {} into place", tmp_path.display()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.config.directory.join(key);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("Failed to delete {}", path.display())),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let directory = &self.config.directory;
+        let mut entries = tokio::fs::read_dir(directory)
+            .await
+            .with_context(|| format!("Failed to read directory {}", directory.display()))?;
+        let mut keys = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+            if let Some(key) = entry.file_name().to_str() {
+                if !key.ends_with(".tmp") {
+                    keys.push(key.to_string());
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::{bail, Context, Result};
+use aptos_move_graphql_scalars::Address;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    str::FromStr,
+};
+
+/// The palette used when no palette is configured. In the contract we just use
+/// different int values to represent different colors, for the sake of optimization.
+/// These are the colors those values map to: black, white, blue, green, yellow,
+/// orange, red and violet.
+const DEFAULT_PALETTE: [RgbColor; 8] = [
+    RgbColor { r: 0, g: 0, b: 0 },
+    RgbColor {
+        r: 255,
+        g: 255,
+        b: 255,
+    },
+    RgbColor {
+        r: 0,
+        g: 158,
+        b: 253,
+    },
+    RgbColor { r: 0, g: 197, b: 3 },
+    RgbColor {
+        r: 255,
+        g: 198,
+        b: 0,
+    },
+    RgbColor {
+        r: 255,
+        g: 125,
+        b: 0,
+    },
+    RgbColor {
+        r: 250,
+        g: 0,
+        b: 106,
+    },
+    RgbColor {
+        r: 196,
+        g: 0,
+        b: 199,
+    },
+];
+
+/// The ID of the palette used for any canvas not configured otherwise.
+pub const DEFAULT_PALETTE_ID: u16 = 0;
+
+/// A color as written in the config, e.g. "#FF7D00".
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RgbColor {
+    pub(crate) r: u8,
+    pub(crate) g: u8,
+    pub(crate) b: u8,
+}
+
+impl FromStr for RgbColor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.is_ascii() {
+            bail!("Color {} is not of the form #RRGGBB", s);
+        }
+        let channel = |i: usize| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .with_context(|| format!("Color {} is not valid hex", s))
+        };
+        Ok(Self {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        })
+    }
+}
+
+impl TryFrom<String> for RgbColor {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        Self::from_str(&s)
+    }
+}
+
+impl From<RgbColor> for String {
+    fn from(color: RgbColor) -> Self {
+        color.to_string()
+    }
+}
+
+impl fmt::Display for RgbColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02X}{:02X}{:02X}", self.r, self.g, self.b)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PalettesConfig {
+    /// Palettes keyed by ID. Each palette is a list of colors, where the value the
+    /// contract uses for a color is its index in the list. If palette 0 isn't given,
+    /// it is the palette from the canvas contract.
+    #[serde(default)]
+    pub palettes: BTreeMap<u16, Vec<RgbColor>>,
+
+    /// Which palette to use for each canvas. Canvases not listed here use palette 0.
+    #[serde(default)]
+    pub canvas_palettes: HashMap<Address, u16>,
+}
+
+/// A validated set of palettes, built from a `PalettesConfig`.
+#[derive(Debug)]
+pub struct Palettes {
+    palettes: BTreeMap<u16, Vec<RgbColor>>,
+    canvas_palettes: HashMap<Address, u16>,
+}
+
+impl Palettes {
+    pub fn new(config: PalettesConfig) -> Result<Self> {
+        let mut palettes = config.palettes;
+        palettes
+            .entry(DEFAULT_PALETTE_ID)
+            .or_insert_with(|| DEFAULT_PALETTE.to_vec());
+
+        for (id, colors) in &palettes {
+            if colors.is_empty() {
+                bail!("Palette {} has no colors", id);
+            }
+            if colors.len() > u8::MAX as usize + 1 {
+                bail!(
+                    "Palette {} has {} colors but the most we support is {}",
+                    id,
+                    colors.len(),
+                    u8::MAX as usize + 1
+                );
+            }
+        }
+
+        for (canvas_address, id) in &config.canvas_palettes {
+            if !palettes.contains_key(id) {
+                bail!(
+                    "Canvas {} is configured to use palette {}, which doesn't exist",
+                    canvas_address,
+                    id
+                );
+            }
+        }
+
+        Ok(Self {
+            palettes,
+            canvas_palettes: config.canvas_palettes,
+        })
+    }
+
+    /// The ID of the palette new canvases at this address should use.
+    pub fn palette_id_for(&self, canvas_address: &Address) -> u16 {
+        self.canvas_palettes
+            .get(canvas_address)
+            .copied()
+            .unwrap_or(DEFAULT_PALETTE_ID)
+    }
+
+    /// The most colors any palette has.
+    pub fn max_len(&self) -> usize {
+        self.palettes.values().map(Vec::len).max().unwrap_or(0)
+    }
+
+    /// Get every color in the given palette.
+    pub fn colors(&self, palette_id: u16) -> Result<&[RgbColor]> {
+        self.palettes
+            .get(&palette_id)
+            .map(Vec::as_slice)
+            .with_context(|| format!("Palette {} doesn't exist", palette_id))
+    }
+
+    /// Look up a color in the given palette.
+    pub fn color(&self, palette_id: u16, index: u8) -> Result<&RgbColor> {
+        let colors = self.colors(palette_id)?;
+        colors.get(index as usize).with_context(|| {
+            format!(
+                "Color {} is out of range for palette {}, which has {} colors",
+                index,
+                palette_id,
+                colors.len()
+            )
+        })
+    }
+
+    /// Find the index of a color in the given palette.
+    pub fn index_of(&self, palette_id: u16, color: &RgbColor) -> Result<u8> {
+        let index = self
+            .colors(palette_id)?
+            .iter()
+            .position(|c| c == color)
+            .with_context(|| format!("Color {} is not in palette {}", color, palette_id))?;
+        Ok(index as u8)
+    }
+}
+
+impl Default for Palettes {
+    fn default() -> Self {
+        Self::new(PalettesConfig::default()).expect("Default palettes should be valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(s: &str) -> RgbColor {
+        RgbColor::from_str(s).unwrap()
+    }
+
+    fn address() -> Address {
+        Address::from_str("0x1").unwrap()
+    }
+
+    #[test]
+    fn test_default_palette() {
+        let palettes = Palettes::default();
+        assert_eq!(
+            palettes.colors(DEFAULT_PALETTE_ID).unwrap(),
+            DEFAULT_PALETTE
+        );
+        assert_eq!(palettes.palette_id_for(&address()), DEFAULT_PALETTE_ID);
+        assert!(palettes.colors(1).is_err());
+        assert!(palettes.color(DEFAULT_PALETTE_ID, 8).is_err());
+    }
+
+    #[test]
+    fn test_canvas_palettes() {
+        let palettes = Palettes::new(PalettesConfig {
+            palettes: BTreeMap::from([(3, vec![color("#123456"), color("#ABCDEF")])]),
+            canvas_palettes: HashMap::from([(address(), 3)]),
+        })
+        .unwrap();
+        assert_eq!(palettes.palette_id_for(&address()), 3);
+        assert_eq!(palettes.color(3, 1).unwrap(), &color("#ABCDEF"));
+        assert_eq!(palettes.index_of(3, &color("#123456")).unwrap(), 0);
+        assert_eq!(palettes.max_len(), DEFAULT_PALETTE.len());
+    }
+
+    #[test]
+    fn test_unknown_palette_id() {
+        let error = Palettes::new(PalettesConfig {
+            palettes: BTreeMap::new(),
+            canvas_palettes: HashMap::from([(address(), 3)]),
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("palette 3"), "{}", error);
+    }
+
+    #[test]
+    fn test_invalid_palettes() {
+        assert!(Palettes::new(PalettesConfig {
+            palettes: BTreeMap::from([(1, vec![])]),
+            canvas_palettes: HashMap::new(),
+        })
+        .is_err());
+        assert!(Palettes::new(PalettesConfig {
+            palettes: BTreeMap::from([(1, vec![color("#000000"); 257])]),
+            canvas_palettes: HashMap::new(),
+        })
+        .is_err());
+        assert!(Palettes::new(PalettesConfig {
+            palettes: BTreeMap::from([(1, vec![color("#000000"); 256])]),
+            canvas_palettes: HashMap::new(),
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(color("#FF7D00"), RgbColor {
+            r: 255,
+            g: 125,
+            b: 0
+        });
+        assert_eq!(color("ff7d00").to_string(), "#FF7D00");
+        assert!(RgbColor::from_str("#FF7D0").is_err());
+        assert!(RgbColor::from_str("#GG7D00").is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use crate::{
+    history::{apply_record, HistoryRecord, HistorySource},
+    utils::{check_scaled_size, scale_pixels},
+    RgbColor,
+};
+use anyhow::{bail, Context, Result};
+use std::{fs, path::Path};
+use tracing::info;
+
+/// How far apart the frames of a timelapse are.
+#[derive(Clone, Copy, Debug)]
+pub enum FrameInterval {
+    Versions(u64),
+    Seconds(u64),
+}
+
+impl FrameInterval {
+    fn len(&self) -> u64 {
+        match self {
+            FrameInterval::Versions(versions) => *versions,
+            FrameInterval::Seconds(seconds) => *seconds,
+        }
+    }
+
+    /// Where the record falls along the axis we're taking frames on.
+    fn key(&self, record: &HistoryRecord) -> u64 {
+        match self {
+            FrameInterval::Versions(_) => record.txn_version,
+            FrameInterval::Seconds(_) => record.txn_timestamp_secs,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TimelapseOptions {
+    pub interval: FrameInterval,
+    /// Each pixel of the canvas becomes a square this many pixels wide.
+    pub scale: u32,
+    /// If the interval would give more frames than this, we widen it so the
+    /// timelapse still covers the whole history of the canvas.
+    pub max_frames: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct TimelapseFrame {
+    /// The version of the last txn included in this frame.
+    pub txn_version: u64,
+    /// The timestamp of the last txn included in this frame.
+    pub txn_timestamp_secs: u64,
+    /// The pixels as indices into the palette, at the size of the canvas.
+    pixels: Vec<u8>,
+}
+
+/// A canvas evolving over time, ready to be encoded in a few different ways. Each
+/// frame is stored as palette indices, which the formats we encode to support
+/// directly, so the output is exact and small. We keep the frames at the size of
+/// the canvas and only scale each one up as we encode it.
+#[derive(Clone, Debug)]
+pub struct Timelapse {
+    /// The size of the encoded frames, i.e. the size of the canvas times the scale.
+    pub width: u32,
+    pub height: u32,
+    canvas_width: u32,
+    canvas_height: u32,
+    scale: u32,
+    palette: Vec<RgbColor>,
+    pub frames: Vec<TimelapseFrame>,
+}
+
+impl Timelapse {
+    fn scaled_pixels(&self, frame: &TimelapseFrame) -> Vec<u8> {
+        scale_pixels(
+            &frame.pixels,
+            self.canvas_width,
+            self.canvas_height,
+            self.scale,
+        )
+    }
+
+    /// Encode the timelapse as an animated PNG that loops forever.
+    pub fn to_apng(&self, frame_delay_ms: u16) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        let mut encoder = self.png_encoder(&mut buffer);
+        encoder
+            .set_animated(self.frames.len() as u32, 0)
+            .context("Failed to make png animated")?;
+        encoder
+            .set_frame_delay(frame_delay_ms, 1000)
+            .context("Failed to set frame delay")?;
+        let mut writer = encoder
+            .write_header()
+            .context("Failed to write png header")?;
+        for frame in &self.frames {
+            writer
+                .write_image_data(&self.scaled_pixels(frame))
+                .context("Failed to write png frame")?;
+        }
+        writer.finish().context("Failed to finish png")?;
+        Ok(buffer)
+    }
+
+    /// Encode the timelapse as a GIF that loops forever. GIF delays are in
+    /// hundredths of a second, so the delay is rounded down to that.
+    pub fn to_gif(&self, frame_delay_ms: u16) -> Result<Vec<u8>> {
+        let width = u16::try_from(self.width).context("Timelapse is too wide for a gif")?;
+        let height = u16::try_from(self.height).context("Timelapse is too tall for a gif")?;
+        let mut buffer = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut buffer, width, height, &self.palette_bytes())
+                .context("Failed to create gif encoder")?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .context("Failed to make gif loop")?;
+            for frame in &self.frames {
+                let pixels = self.scaled_pixels(frame);
+                let mut gif_frame =
+                    gif::Frame::from_indexed_pixels(width, height, pixels.as_slice(), None);
+                gif_frame.delay = frame_delay_ms / 10;
+                encoder
+                    .write_frame(&gif_frame)
+                    .context("Failed to write gif frame")?;
+            }
+        }
+        Ok(buffer)
+    }
+
+    /// Write each frame as a png named like `frame_00042.png` in the given directory,
+    /// creating it if necessary.
+    pub fn write_frames(&self, directory: &Path) -> Result<()> {
+        fs::create_dir_all(directory)
+            .with_context(|| format!("Failed to create directory {}", directory.display()))?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            let mut buffer = Vec::new();
+            let mut writer = self
+                .png_encoder(&mut buffer)
+                .write_header()
+                .context("Failed to write png header")?;
+            writer
+                .write_image_data(&self.scaled_pixels(frame))
+                .context("Failed to write png")?;
+            writer.finish().context("Failed to finish png")?;
+
+            let path = directory.join(format!("frame_{:05}.png", i));
+            fs::write(&path, buffer)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn png_encoder<'a>(&self, buffer: &'a mut Vec<u8>) -> png::Encoder<'a, &'a mut Vec<u8>> {
+        let mut encoder = png::Encoder::new(buffer, self.width, self.height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(self.palette_bytes());
+        encoder
+    }
+
+    fn palette_bytes(&self) -> Vec<u8> {
+        self.palette
+            .iter()
+            .flat_map(|color| [color.r, color.g, color.b])
+            .collect()
+    }
+}
+
+/// Replay the history, taking a frame every interval. The first frame is the
+/// canvas as it was created and the last is the canvas after the last record.
+pub fn render_timelapse(
+    history: HistorySource,
+    width: u32,
+    height: u32,
+    palette: &[RgbColor],
+    options: &TimelapseOptions,
+) -> Result<Timelapse> {
+    if options.scale == 0 {
+        bail!("Scale must be at least 1");
+    }
+    if options.max_frames == 0 {
+        bail!("Max frames must be at least 1");
+    }
+    let scaled_width = width
+        .checked_mul(options.scale)
+        .context("Timelapse would be too wide")?;
+    let scaled_height = height
+        .checked_mul(options.scale)
+        .context("Timelapse would be too tall")?;
+    check_scaled_size(scaled_width, scaled_height)?;
+    let num_pixels = width as usize * height as usize;
+
+    // Find how much history there is so we can widen the interval if need be.
+    let mut range: Option<(u64, u64)> = None;
+    history.for_each_record(|record| {
+        let key = options.interval.key(&record);
+        range = Some(match range {
+            Some((first, last)) => (first.min(key), last.max(key)),
+            None => (key, key),
+        });
+        Ok(())
+    })?;
+    let (first, last) = range.context("History is empty")?;
+    let mut interval = options.interval.len().max(1);
+    let gaps = (options.max_frames as u64 - 1).max(1);
+    let min_interval = ((last - first) + gaps - 1) / gaps;
+    if interval < min_interval {
+        info!(
+            "Widening the timelapse interval from {} to {} to stay within {} frames",
+            interval, min_interval, options.max_frames
+        );
+        interval = min_interval;
+    }
+
+    let take_frame = |pixels: &[u8], record: &HistoryRecord| TimelapseFrame {
+        txn_version: record.txn_version,
+        txn_timestamp_secs: record.txn_timestamp_secs,
+        pixels: pixels.to_vec(),
+    };
+
+    // Each time we reach a record past the next frame boundary, the canvas as it
+    // is before that record is the frame for that boundary. If nothing happened for
+    // a few intervals we repeat the frame, so time passes at the same rate
+    // throughout the timelapse.
+    let mut pixels: Option<Vec<u8>> = None;
+    let mut frames = Vec::new();
+    let mut next_boundary = first;
+    let mut last_record = None;
+    history.for_each_record(|record| {
+        let key = options.interval.key(&record);
+        if let (Some(pixels), Some(last_record)) = (&pixels, &last_record) {
+            while key > next_boundary {
+                frames.push(take_frame(pixels, last_record));
+                next_boundary += interval;
+            }
+        }
+        if record.event.color() as usize >= palette.len() {
+            bail!(
+                "Color {} at version {} is not in the palette",
+                record.event.color(),
+                record.txn_version
+            );
+        }
+        apply_record(&mut pixels, num_pixels, &record)?;
+        last_record = Some(record);
+        Ok(())
+    })?;
+    let pixels = pixels.context("History does not start with the creation of the canvas")?;
+    let last_record = last_record.context("History is empty")?;
+    frames.push(take_frame(&pixels, &last_record));
+
+    // The final frame can push us one over the limit, in which case we drop the one
+    // before it.
+    while frames.len() > options.max_frames {
+        frames.remove(frames.len() - 2);
+    }
+
+    Ok(Timelapse {
+        width: scaled_width,
+        height: scaled_height,
+        canvas_width: width,
+        canvas_height: height,
+        scale: options.scale,
+        palette: palette.to_vec(),
+        frames,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::HistoryEvent;
+    use aptos_move_graphql_scalars::Address;
+    use std::str::FromStr;
+
+    /// A canvas created at version 0 with a pixel drawn at every version up to and
+    /// including 100, each a second apart.
+    fn records() -> Vec<HistoryRecord> {
+        let artist_address = Address::from_str("0x1").unwrap();
+        (0..=100)
+            .map(|version| HistoryRecord {
+                txn_version: version,
+                txn_timestamp_secs: 1000 + version,
+                event: match version {
+                    0 => HistoryEvent::Create { default_color: 0 },
+                    _ => HistoryEvent::Draw {
+                        index: (version % 4) as u32,
+                        color: (version % 2) as u8,
+                        artist_address,
+                    },
+                },
+            })
+            .collect()
+    }
+
+    fn frame_versions(interval: FrameInterval, max_frames: usize) -> Vec<u64> {
+        let records = records();
+        let palette = [
+            RgbColor::from_str("#000000").unwrap(),
+            RgbColor::from_str("#FFFFFF").unwrap(),
+        ];
+        let options = TimelapseOptions {
+            interval,
+            scale: 1,
+            max_frames,
+        };
+        render_timelapse(HistorySource::Records(&records), 2, 2, &palette, &options)
+            .unwrap()
+            .frames
+            .iter()
+            .map(|frame| frame.txn_version)
+            .collect()
+    }
+
+    #[test]
+    fn test_interval() {
+        assert_eq!(frame_versions(FrameInterval::Versions(25), 100), [
+            0, 25, 50, 75, 100
+        ]);
+        assert_eq!(frame_versions(FrameInterval::Versions(1), 200).len(), 101);
+    }
+
+    #[test]
+    fn test_interval_widened_to_max_frames() {
+        assert_eq!(frame_versions(FrameInterval::Versions(1), 5), [
+            0, 25, 50, 75, 100
+        ]);
+        assert_eq!(frame_versions(FrameInterval::Seconds(1), 5), [
+            0, 25, 50, 75, 100
+        ]);
+        for max_frames in 1..=20 {
+            let versions = frame_versions(FrameInterval::Versions(1), max_frames);
+            assert!(versions.len() <= max_frames);
+            assert_eq!(versions.last(), Some(&100));
+        }
+    }
+}
@@ -1,6 +1,17 @@
-use crate::RgbColor;
-use anyhow::{Context, Result};
-use image::{codecs::png::PngEncoder, ColorType, ImageBuffer, ImageEncoder, Rgb};
+use crate::{
+    CreateCanvasIntent, ImageFormat, PixelStorageError, PixelStorageResult, RgbColor, Scale,
+    WritePixelIntent,
+};
+use anyhow::{bail, Context, Result};
+use image::{
+    codecs::{bmp::BmpEncoder, png::PngEncoder},
+    ColorType, ImageBuffer, ImageEncoder, Rgb,
+};
+use image_webp::WebPEncoder;
+use tracing::info;
+
+/// The widest or tallest we'll scale an image to, to keep memory use in check.
+pub const MAX_SCALED_SIZE: u32 = 8192;
 
 /// Convert a vector of Colors to a png.
 pub fn get_image(pixels: Vec<RgbColor>, width: u32, height: u32) -> Result<Vec<u8>> {
@@ -24,3 +35,247 @@ pub fn get_image(pixels: Vec<RgbColor>, width: u32, height: u32) -> Result<Vec<u
 
     Ok(buffer)
 }
+
+/// Encode a vector of Colors in the given format. The raw format needs palette
+/// indices rather than colors, see `encode_raw` for that.
+pub fn encode_image(
+    pixels: Vec<RgbColor>,
+    width: u32,
+    height: u32,
+    format: ImageFormat,
+) -> Result<Vec<u8>> {
+    if format == ImageFormat::Png {
+        return get_image(pixels, width, height);
+    }
+
+    let data = pixels
+        .iter()
+        .flat_map(|color| [color.r, color.g, color.b])
+        .collect::<Vec<_>>();
+    let mut buffer: Vec<u8> = Vec::new();
+    match format {
+        ImageFormat::Webp => WebPEncoder::new(&mut buffer)
+            .encode(&data, width, height, image_webp::ColorType::Rgb8)
+            .context("Failed to create webp")?,
+        ImageFormat::Bmp => BmpEncoder::new(&mut buffer)
+            .encode(&data, width, height, ColorType::Rgb8)
+            .context("Failed to create bmp")?,
+        ImageFormat::Png | ImageFormat::Raw => {
+            bail!("Can't encode colors as {:?}", format)
+        },
+    }
+    Ok(buffer)
+}
+
+/// Encode palette indices in the raw format, see `ImageFormat::Raw`.
+pub fn encode_raw(indices: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(8 + indices.len());
+    buffer.extend(width.to_le_bytes());
+    buffer.extend(height.to_le_bytes());
+    buffer.extend(indices);
+    buffer
+}
+
+/// Scale up the pixels of an image by an integer factor, so each pixel becomes a
+/// `scale` by `scale` square. This keeps the hard edges of pixel art, which smooth
+/// resizing would blur.
+pub fn scale_pixels<T: Copy>(pixels: &[T], width: u32, height: u32, scale: u32) -> Vec<T> {
+    if scale == 1 {
+        return pixels.to_vec();
+    }
+    let (width, height, scale) = (width as usize, height as usize, scale as usize);
+    let mut scaled = Vec::with_capacity(pixels.len() * scale * scale);
+    for y in 0..height {
+        let row = &pixels[y * width..(y + 1) * width];
+        let scaled_row_start = scaled.len();
+        for pixel in row {
+            scaled.extend(std::iter::repeat(*pixel).take(scale));
+        }
+        for _ in 1..scale {
+            scaled.extend_from_within(scaled_row_start..scaled_row_start + width * scale);
+        }
+    }
+    scaled
+}
+
+/// Scale down the pixels of an image by an integer factor, averaging each `scale` by
+/// `scale` square into one pixel. If the image doesn't divide evenly, the squares
+/// along the right and bottom edges are averaged over just the pixels they cover.
+pub fn downscale_pixels(pixels: &[RgbColor], width: u32, height: u32, scale: u32) -> Vec<RgbColor> {
+    let (width, height, scale) = (width as usize, height as usize, scale as usize);
+    let scaled_width = (width + scale - 1) / scale;
+    let scaled_height = (height + scale - 1) / scale;
+    let mut scaled = Vec::with_capacity(scaled_width * scaled_height);
+    for scaled_y in 0..scaled_height {
+        for scaled_x in 0..scaled_width {
+            let (mut r, mut g, mut b, mut count) = (0u64, 0u64, 0u64, 0u64);
+            for y in scaled_y * scale..((scaled_y + 1) * scale).min(height) {
+                for x in scaled_x * scale..((scaled_x + 1) * scale).min(width) {
+                    let color = &pixels[y * width + x];
+                    r += color.r as u64;
+                    g += color.g as u64;
+                    b += color.b as u64;
+                    count += 1;
+                }
+            }
+            scaled.push(RgbColor {
+                r: (r / count) as u8,
+                g: (g / count) as u8,
+                b: (b / count) as u8,
+            });
+        }
+    }
+    scaled
+}
+
+/// Resize an image to any size. Each output pixel is the average of the input pixels
+/// it covers (a box filter), which when growing the image is just the nearest pixel.
+pub fn resize_pixels(
+    pixels: &[RgbColor],
+    width: u32,
+    height: u32,
+    new_width: u32,
+    new_height: u32,
+) -> Vec<RgbColor> {
+    // The range of input pixels covered by output pixel `i` along one axis.
+    let span = |i: u32, len: u32, new_len: u32| {
+        let start = (i as u64 * len as u64 / new_len as u64) as usize;
+        let end = ((i as u64 + 1) * len as u64 / new_len as u64) as usize;
+        start..end.max(start + 1)
+    };
+    let mut resized = Vec::with_capacity(new_width as usize * new_height as usize);
+    for new_y in 0..new_height {
+        let ys = span(new_y, height, new_height);
+        for new_x in 0..new_width {
+            let xs = span(new_x, width, new_width);
+            let (mut r, mut g, mut b, mut count) = (0u64, 0u64, 0u64, 0u64);
+            for y in ys.clone() {
+                for color in &pixels[y * width as usize + xs.start..y * width as usize + xs.end] {
+                    r += color.r as u64;
+                    g += color.g as u64;
+                    b += color.b as u64;
+                    count += 1;
+                }
+            }
+            resized.push(RgbColor {
+                r: (r / count) as u8,
+                g: (g / count) as u8,
+                b: (b / count) as u8,
+            });
+        }
+    }
+    resized
+}
+
+/// Scale an image, returning the scaled pixels along with the new width and height.
+pub fn scale_image(
+    pixels: Vec<RgbColor>,
+    width: u32,
+    height: u32,
+    scale: Scale,
+) -> Result<(Vec<RgbColor>, u32, u32)> {
+    let (scaled_width, scaled_height) = match scale {
+        Scale::Up(0) | Scale::Down(0) | Scale::ToWidth(0) => {
+            bail!("Scale and width must be at least 1")
+        },
+        Scale::Up(1) | Scale::Down(1) => return Ok((pixels, width, height)),
+        Scale::Up(scale) => (width.saturating_mul(scale), height.saturating_mul(scale)),
+        Scale::Down(scale) => ((width + scale - 1) / scale, (height + scale - 1) / scale),
+        Scale::ToWidth(new_width) => {
+            let new_height = (height as u64 * new_width as u64 + width as u64 / 2) / width as u64;
+            (new_width, new_height.clamp(1, u32::MAX as u64) as u32)
+        },
+    };
+    check_scaled_size(scaled_width, scaled_height)?;
+
+    let scaled = match scale {
+        Scale::Up(scale) => scale_pixels(&pixels, width, height, scale),
+        Scale::Down(scale) => downscale_pixels(&pixels, width, height, scale),
+        Scale::ToWidth(_) => resize_pixels(&pixels, width, height, scaled_width, scaled_height),
+    };
+    Ok((scaled, scaled_width, scaled_height))
+}
+
+/// Scale an image made of palette indices. Indices can't be averaged, so this only
+/// supports scaling up.
+pub fn scale_indices(
+    indices: Vec<u8>,
+    width: u32,
+    height: u32,
+    scale: Scale,
+) -> Result<(Vec<u8>, u32, u32)> {
+    match scale {
+        Scale::Up(0) => bail!("Scale must be at least 1"),
+        Scale::Up(1) | Scale::Down(1) => Ok((indices, width, height)),
+        Scale::Up(scale) => {
+            let (scaled_width, scaled_height) =
+                (width.saturating_mul(scale), height.saturating_mul(scale));
+            check_scaled_size(scaled_width, scaled_height)?;
+            Ok((
+                scale_pixels(&indices, width, height, scale),
+                scaled_width,
+                scaled_height,
+            ))
+        },
+        Scale::Down(_) | Scale::ToWidth(_) => bail!("Raw images can only be scaled up"),
+    }
+}
+
+pub(crate) fn check_scaled_size(width: u32, height: u32) -> PixelStorageResult<()> {
+    if width > MAX_SCALED_SIZE || height > MAX_SCALED_SIZE {
+        return Err(PixelStorageError::ScaledImageTooLarge { width, height });
+    }
+    Ok(())
+}
+
+/// Drop anything from a txn we've already applied to a canvas, i.e. with a version
+/// at or before the last applied version. This is how we avoid applying txns twice
+/// when the processor replays them after a restart.
+pub fn skip_applied<T>(
+    items: Vec<T>,
+    last_applied_version: u64,
+    version: impl Fn(&T) -> u64,
+) -> Vec<T> {
+    items
+        .into_iter()
+        .filter(|item| version(item) > last_applied_version)
+        .collect()
+}
+
+/// Handle a create for a canvas that already exists. If we have already applied the
+/// create (or anything after it) this is the processor replaying txns after a
+/// restart, so we do nothing. Otherwise something is wrong, so rather than wiping
+/// the canvas we fail. A last applied version of zero means we don't know what has
+/// been applied, e.g. for legacy files, in which case we assume it is a replay.
+pub fn check_existing_canvas(intent: &CreateCanvasIntent, last_applied_version: u64) -> Result<()> {
+    if last_applied_version != 0 && last_applied_version < intent.txn_version {
+        bail!(
+            "Canvas {} already exists with changes up to version {}, refusing to recreate it at version {}",
+            intent.canvas_address,
+            last_applied_version,
+            intent.txn_version
+        );
+    }
+    info!(
+        "Canvas {} already exists, skipping create at version {}",
+        intent.canvas_address, intent.txn_version
+    );
+    Ok(())
+}
+
+/// Make sure the pixel is within a canvas of this size.
+pub fn check_in_bounds(
+    intent: &WritePixelIntent,
+    width: u32,
+    height: u32,
+) -> PixelStorageResult<()> {
+    if intent.index as u64 >= width as u64 * height as u64 {
+        return Err(PixelStorageError::OutOfBounds {
+            canvas_address: intent.canvas_address,
+            index: intent.index,
+            width,
+            height,
+        });
+    }
+    Ok(())
+}
@@ -1,5 +1,7 @@
 mod processor;
 mod run;
 
-pub use processor::{CanvasProcessor, CanvasProcessorConfig};
+pub use processor::{
+    CanvasProcessor, CanvasProcessorConfig, InvalidPixelPolicy, CANVAS_PROCESSOR_NAME,
+};
 pub use run::{run, RunConfig};
@@ -10,14 +10,24 @@ use aptos_processor_framework::{
 };
 use metadata_storage::{MetadataStorageTrait, UpdateAttributionIntent};
 use move_types::{Canvas, Entry, Object};
-use pixel_storage::{CreateCanvasIntent, HardcodedColor, PixelStorageTrait, WritePixelIntent};
+use pixel_storage::{
+    ArchiveCanvasIntent, ClearCanvasIntent, CreateCanvasIntent, Palettes, PixelStorageError,
+    PixelStorageTrait, WritePixelIntent,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{str::FromStr, sync::Arc};
-use tracing::info;
+use std::{
+    collections::{hash_map, HashMap},
+    str::FromStr,
+    sync::Arc,
+};
+use tracing::{info, warn};
 
 const CANVAS_TOKEN_MODULE_NAME: &str = "canvas_token";
 
+/// The name the processor keeps its last processed version under.
+pub const CANVAS_PROCESSOR_NAME: &str = "CanvasProcessor";
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct CanvasProcessorConfig {
@@ -31,6 +41,22 @@ pub struct CanvasProcessorConfig {
     /// If set, disable pixel processing and only process metadata.
     #[serde(default)]
     pub disable_pixel_processing: bool,
+
+    /// What to do with a pixel we can't write, e.g. because it is outside the canvas
+    /// or its color isn't in the palette. Pixels for archived canvases are always
+    /// skipped, see `process_burn`.
+    #[serde(default)]
+    pub invalid_pixel_policy: InvalidPixelPolicy,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidPixelPolicy {
+    /// Fail the whole batch. The processor stops until the problem is fixed.
+    #[default]
+    Fail,
+    /// Log the pixel and carry on without it.
+    Skip,
 }
 
 #[derive(Debug)]
@@ -38,6 +64,7 @@ pub struct CanvasProcessor {
     config: CanvasProcessorConfig,
     pixels_storage: Arc<dyn PixelStorageTrait>,
     metadata_storage: Arc<dyn MetadataStorageTrait>,
+    palettes: Arc<Palettes>,
 }
 
 impl CanvasProcessor {
@@ -45,6 +72,7 @@ impl CanvasProcessor {
         config: CanvasProcessorConfig,
         pixels_storage: Arc<dyn PixelStorageTrait>,
         metadata_storage: Arc<dyn MetadataStorageTrait>,
+        palettes: Arc<Palettes>,
     ) -> Result<Self> {
         if config.disable_metadata_processing && config.disable_pixel_processing {
             bail!("disable_metadata_processing and disable_pixel_processing are both set to true, this is invalid");
@@ -53,6 +81,7 @@ impl CanvasProcessor {
             config,
             pixels_storage,
             metadata_storage,
+            palettes,
         })
     }
 
@@ -66,11 +95,20 @@ impl CanvasProcessor {
     }
 }
 
+/// A change a txn makes to pixel storage. We apply these in txn order.
+#[derive(Debug)]
+enum PixelOperation {
+    Create(CreateCanvasIntent),
+    Write(Vec<WritePixelIntent>),
+    Clear(ClearCanvasIntent),
+    Archive(ArchiveCanvasIntent),
+}
+
 /// A processor that just prints the txn version.
 #[async_trait::async_trait]
 impl ProcessorTrait for CanvasProcessor {
     fn name(&self) -> &'static str {
-        "CanvasProcessor"
+        CANVAS_PROCESSOR_NAME
     }
 
     async fn process_transactions(
@@ -79,9 +117,7 @@ impl ProcessorTrait for CanvasProcessor {
         start_version: u64,
         end_version: u64,
     ) -> Result<ProcessingResult> {
-        let mut all_create_canvas_intents = Vec::new();
-        let mut all_write_pixel_intents = Vec::new();
-        let mut all_update_attribution_intents = Vec::new();
+        let mut operations = Vec::new();
         for transaction in transactions {
             // Skip failed transactions.
             if let Some(info) = &transaction.info {
@@ -90,60 +126,157 @@ impl ProcessorTrait for CanvasProcessor {
                 }
             }
 
-            // todo process canvas_token::create and create images for that
-            // todo create a storage interface with like create that takes in a default color
-            // a width and height, then methods for writing pixels to it, and also reading
-            // the full thing. it should handle the read update write process inside it
-            let (write_pixel_intents, update_attribution_intents) =
-                self.process_draw(&transaction).context(format!(
-                    "Failed at process_draw for txn version {}",
-                    transaction.version
-                ))?;
-            all_write_pixel_intents.extend(write_pixel_intents);
-            all_update_attribution_intents.extend(update_attribution_intents);
             let create_canvas_intent = self.process_create(&transaction).context(format!(
                 "Failed at process_create for txn version {}",
                 transaction.version
             ))?;
-            if let Some(create_canvas_intent) = create_canvas_intent {
-                all_create_canvas_intents.push(create_canvas_intent);
+            operations.extend(create_canvas_intent.map(PixelOperation::Create));
+            let write_pixel_intents = self.process_draw(&transaction).context(format!(
+                "Failed at process_draw for txn version {}",
+                transaction.version
+            ))?;
+            if !write_pixel_intents.is_empty() {
+                operations.push(PixelOperation::Write(write_pixel_intents));
             }
+            let clear_canvas_intent = self.process_clear(&transaction).context(format!(
+                "Failed at process_clear for txn version {}",
+                transaction.version
+            ))?;
+            operations.extend(clear_canvas_intent.map(PixelOperation::Clear));
+            operations.extend(
+                self.process_burn(&transaction)
+                    .into_iter()
+                    .map(PixelOperation::Archive),
+            );
         }
+        let count = |f: fn(&PixelOperation) -> usize| operations.iter().map(f).sum::<usize>();
         info!(
             start_version = start_version,
             end_version = end_version,
             processor_name = self.name(),
-            num_canvases_to_create = all_create_canvas_intents.len(),
-            num_pixels_to_write = all_write_pixel_intents.len()
+            num_canvases_to_create =
+                count(|operation| matches!(operation, PixelOperation::Create(_)) as usize),
+            num_canvases_to_clear =
+                count(|operation| matches!(operation, PixelOperation::Clear(_)) as usize),
+            num_canvases_to_archive =
+                count(|operation| matches!(operation, PixelOperation::Archive(_)) as usize),
+            num_pixels_to_write = count(|operation| match operation {
+                PixelOperation::Write(intents) => intents.len(),
+                _ => 0,
+            })
         );
 
-        if !self.config.disable_pixel_processing {
-            // Create canvases.
-            for create_canvas_intent in all_create_canvas_intents {
-                info!("Creating canvas {}", create_canvas_intent.canvas_address);
-                self.pixels_storage
-                    .create_canvas(create_canvas_intent)
-                    .await
-                    .context("Failed to create canvas in storage")?;
+        // The clears we applied, so we know which attribution to delete.
+        let mut all_clear_canvas_intents = Vec::new();
+        let mut all_write_pixel_intents = Vec::new();
+        if self.config.disable_pixel_processing {
+            for operation in operations {
+                match operation {
+                    PixelOperation::Write(intents) => all_write_pixel_intents.extend(intents),
+                    PixelOperation::Clear(intent) => all_clear_canvas_intents.push(intent),
+                    PixelOperation::Create(_) | PixelOperation::Archive(_) => {},
+                }
+            }
+        } else {
+            // We apply everything in txn order, so every draw makes it into the
+            // history of its canvas, even if the canvas is cleared later in the
+            // batch. To keep writes batched, we build up the pixels to write and only
+            // write them when something else is about to happen to one of their
+            // canvases, or at the end.
+            let mut pending_write_pixel_intents = Vec::new();
+            for operation in operations {
+                let canvas_address = match &operation {
+                    PixelOperation::Write(_) => None,
+                    PixelOperation::Create(intent) => Some(intent.canvas_address),
+                    PixelOperation::Clear(intent) => Some(intent.canvas_address),
+                    PixelOperation::Archive(intent) => Some(intent.canvas_address),
+                };
+                if let Some(canvas_address) = canvas_address {
+                    if pending_write_pixel_intents
+                        .iter()
+                        .any(|intent: &WritePixelIntent| intent.canvas_address == canvas_address)
+                    {
+                        all_write_pixel_intents.extend(
+                            self.write_pixels(std::mem::take(&mut pending_write_pixel_intents))
+                                .await?,
+                        );
+                    }
+                }
+                match operation {
+                    PixelOperation::Write(intents) => pending_write_pixel_intents.extend(intents),
+                    PixelOperation::Create(intent) => {
+                        info!("Creating canvas {}", intent.canvas_address);
+                        self.pixels_storage
+                            .create_canvas(intent)
+                            .await
+                            .context("Failed to create canvas in storage")?;
+                    },
+                    PixelOperation::Clear(intent) => {
+                        if self.is_canvas_archived(&intent.canvas_address).await? {
+                            warn!(
+                                "Skipping clear of canvas {} from txn {}: the canvas is archived",
+                                intent.canvas_address, intent.txn_version
+                            );
+                            continue;
+                        }
+                        info!("Clearing canvas {}", intent.canvas_address);
+                        self.pixels_storage
+                            .clear_canvas(intent.clone())
+                            .await
+                            .context("Failed to clear canvas in storage")?;
+                        all_clear_canvas_intents.push(intent);
+                    },
+                    PixelOperation::Archive(intent) => {
+                        info!("Archiving canvas {}", intent.canvas_address);
+                        self.pixels_storage
+                            .archive_canvas(intent)
+                            .await
+                            .context("Failed to archive canvas in storage")?;
+                    },
+                }
+            }
+            if !pending_write_pixel_intents.is_empty() {
+                // Don't attribute pixels that were never drawn.
+                all_write_pixel_intents
+                    .extend(self.write_pixels(pending_write_pixel_intents).await?);
             }
+        }
 
-            // Write pixels.
-            if !all_write_pixel_intents.is_empty() {
+        if !self.config.disable_metadata_processing {
+            // Delete attribution for cleared canvases.
+            for clear_canvas_intent in &all_clear_canvas_intents {
                 info!(
-                    "Writing {} pixels (from txns {} to {})",
-                    all_write_pixel_intents.len(),
-                    start_version,
-                    end_version
+                    "Deleting attribution for canvas {}",
+                    clear_canvas_intent.canvas_address
                 );
-                self.pixels_storage
-                    .write_pixels(all_write_pixel_intents)
+                self.metadata_storage
+                    .delete_attribution(&clear_canvas_intent.canvas_address)
                     .await
-                    .context("Failed to write pixel in storage")?;
+                    .context("Failed to delete attribution in storage")?;
             }
-        }
 
-        if !self.config.disable_metadata_processing {
-            // Update attribution.
+            // Update attribution. Pixels drawn before a clear in this batch have
+            // been wiped, so we don't attribute them.
+            let mut last_clear_versions = HashMap::new();
+            for intent in &all_clear_canvas_intents {
+                last_clear_versions.insert(intent.canvas_address, intent.txn_version);
+            }
+            let all_update_attribution_intents: Vec<_> = all_write_pixel_intents
+                .iter()
+                .filter(|intent| {
+                    last_clear_versions
+                        .get(&intent.canvas_address)
+                        .map_or(true, |version| intent.txn_version > *version)
+                })
+                .map(|intent| UpdateAttributionIntent {
+                    canvas_address: intent.canvas_address,
+                    artist_address: intent.artist_address,
+                    index: intent.index,
+                    // The contract doesn't store when a pixel was drawn, so we use
+                    // the timestamp of the txn that drew it.
+                    drawn_at_secs: intent.txn_timestamp_secs,
+                })
+                .collect();
             let len = all_update_attribution_intents.len();
             for (i, update_attribution_intent) in
                 all_update_attribution_intents.into_iter().enumerate()
@@ -169,11 +302,71 @@ impl ProcessorTrait for CanvasProcessor {
 }
 
 impl CanvasProcessor {
-    fn process_draw(
+    /// Write the pixels to storage, applying the invalid pixel policy. Returns the
+    /// pixels we wrote.
+    async fn write_pixels(&self, intents: Vec<WritePixelIntent>) -> Result<Vec<WritePixelIntent>> {
+        let intents = self.drop_invalid_pixels(intents).await?;
+        self.pixels_storage
+            .write_pixels(intents.clone())
+            .await
+            .context("Failed to write pixels in storage")?;
+        Ok(intents)
+    }
+
+    /// Check each pixel against the canvas it's for, dropping the ones storage would
+    /// reject. We always drop pixels for archived canvases, other invalid pixels only
+    /// if the policy is to skip them. We look up each canvas once, so this is cheap
+    /// however many pixels are invalid.
+    async fn drop_invalid_pixels(
         &self,
-        transaction: &Transaction,
-    ) -> Result<(Vec<WritePixelIntent>, Vec<UpdateAttributionIntent>)> {
-        let nothing = Ok((vec![], vec![]));
+        intents: Vec<WritePixelIntent>,
+    ) -> Result<Vec<WritePixelIntent>> {
+        let mut infos = HashMap::new();
+        for intent in &intents {
+            if let hash_map::Entry::Vacant(entry) = infos.entry(intent.canvas_address) {
+                let info = match self.pixels_storage.get_canvas_info(entry.key()).await {
+                    Ok(info) => Some(info),
+                    Err(e) if is_canvas_not_found(&e) => None,
+                    Err(e) => return Err(e).context("Failed to get canvas info"),
+                };
+                entry.insert(info);
+            }
+        }
+        let mut valid_intents = Vec::with_capacity(intents.len());
+        for intent in intents {
+            let result = match &infos[&intent.canvas_address] {
+                Some(info) if info.archived => Err(PixelStorageError::CanvasArchived {
+                    canvas_address: intent.canvas_address,
+                }),
+                _ if self.config.invalid_pixel_policy == InvalidPixelPolicy::Fail => Ok(()),
+                Some(info) => info.check_pixel(&self.palettes, &intent),
+                None => Err(PixelStorageError::CanvasNotFound {
+                    canvas_address: intent.canvas_address,
+                }),
+            };
+            match result {
+                Ok(()) => valid_intents.push(intent),
+                Err(e) => warn!(
+                    "Skipping pixel {} on canvas {} from txn {}: {}",
+                    intent.index, intent.canvas_address, intent.txn_version, e
+                ),
+            }
+        }
+        Ok(valid_intents)
+    }
+
+    /// Whether the canvas is archived. If it doesn't exist we leave it to storage to
+    /// complain.
+    async fn is_canvas_archived(&self, canvas_address: &Address) -> Result<bool> {
+        match self.pixels_storage.get_canvas_info(canvas_address).await {
+            Ok(info) => Ok(info.archived),
+            Err(e) if is_canvas_not_found(&e) => Ok(false),
+            Err(e) => Err(e).context("Failed to get canvas info"),
+        }
+    }
+
+    fn process_draw(&self, transaction: &Transaction) -> Result<Vec<WritePixelIntent>> {
+        let nothing = Ok(vec![]);
 
         // Skip this transaction if this wasn't a draw transaction.
         let draw_function_id = EntryFunctionId {
@@ -225,9 +418,9 @@ impl CanvasProcessor {
 
         let sender =
             Address::from_str(&request.sender).context("Failed to parse sender address")?;
+        let txn_timestamp_secs = get_timestamp_secs(transaction)?;
 
         let mut write_pixel_intents = vec![];
-        let mut update_attribution_intents = vec![];
 
         for change in &info.changes {
             match change.change.as_ref().context("No change")? {
@@ -246,19 +439,17 @@ impl CanvasProcessor {
                         let value: Entry =
                             serde_json::from_value(value).context("Failed to parse as Entry")?;
                         let index = value.key.as_u64().unwrap() as u32;
-                        let hardcoded_color_raw: u8 = serde_json::from_value(value.value).unwrap();
+                        let color: u8 =
+                            serde_json::from_value(value.value).context("Failed to parse color")?;
+                        // We check the color against the palette the canvas was
+                        // created with when we write it, see `write_pixels`.
                         write_pixel_intents.push(WritePixelIntent {
                             canvas_address,
                             index,
-                            color: HardcodedColor::from(hardcoded_color_raw),
-                        });
-                        update_attribution_intents.push(UpdateAttributionIntent {
-                            canvas_address,
+                            color,
                             artist_address: sender,
-                            index,
-                            // This information is gone from the contract so we just
-                            // use a hardcoded value for now.
-                            drawn_at_secs: 42,
+                            txn_version: transaction.version,
+                            txn_timestamp_secs,
                         });
                     }
                 },
@@ -266,7 +457,7 @@ impl CanvasProcessor {
             }
         }
 
-        Ok((write_pixel_intents, update_attribution_intents))
+        Ok(write_pixel_intents)
     }
 
     fn process_create(&self, transaction: &Transaction) -> Result<Option<CreateCanvasIntent>> {
@@ -293,11 +484,14 @@ impl CanvasProcessor {
                     }
                     let canvas: Canvas =
                         serde_json::from_str(&resource.data).context("Failed to parse Canvas")?;
+                    let canvas_address = Address::from_str(&resource.address).unwrap();
                     return Ok(Some(CreateCanvasIntent {
-                        canvas_address: Address::from_str(&resource.address).unwrap(),
+                        canvas_address,
                         width: canvas.config.width,
                         height: canvas.config.height,
-                        default_color: HardcodedColor::from(canvas.config.default_color),
+                        default_color: canvas.config.default_color,
+                        txn_version: transaction.version,
+                        txn_timestamp_secs: get_timestamp_secs(transaction)?,
                     }));
                 },
                 _ => continue,
@@ -305,6 +499,92 @@ impl CanvasProcessor {
         }
         Ok(None)
     }
+
+    fn process_clear(&self, transaction: &Transaction) -> Result<Option<ClearCanvasIntent>> {
+        // Skip this transaction if this wasn't a clear transaction.
+        let clear_function_id = EntryFunctionId {
+            module: Some(MoveModuleId {
+                address: self.config.canvas_contract_address.clone(),
+                name: CANVAS_TOKEN_MODULE_NAME.to_string(),
+            }),
+            name: "clear".to_string(),
+        };
+        if !entry_function_id_matches(transaction, &clear_function_id) {
+            return Ok(None);
+        }
+
+        let info = transaction.info.as_ref().context("No info")?;
+
+        // Clearing moves the Canvas out and back in again with a fresh pixels table,
+        // so we get the Canvas resource in the writeset. From that we can read the
+        // default color to reset the canvas to.
+        for change in &info.changes {
+            match change.change.as_ref().context("No change")? {
+                Change::WriteResource(resource) => {
+                    if resource.r#type.as_ref().unwrap() != &self.get_canvas_struct_tag() {
+                        continue;
+                    }
+                    let canvas: Canvas =
+                        serde_json::from_str(&resource.data).context("Failed to parse Canvas")?;
+                    let canvas_address = Address::from_str(&resource.address).unwrap();
+                    return Ok(Some(ClearCanvasIntent {
+                        canvas_address,
+                        default_color: canvas.config.default_color,
+                        txn_version: transaction.version,
+                        txn_timestamp_secs: get_timestamp_secs(transaction)?,
+                    }));
+                },
+                _ => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Look for canvases that were burned, i.e. had their Canvas resource deleted.
+    /// The canvas_token module never deletes Canvas today, so this never finds
+    /// anything, it's here for a future version of the contract that does. Until
+    /// then canvases are only archived by hand, see `archive-canvas` in the service.
+    /// Either way the canvas might still get draws and clears on chain, which we
+    /// skip with a warning, see `drop_invalid_pixels`.
+    ///
+    /// There is no burn function to match on, so we look at the writeset of every
+    /// txn rather than matching an entry function.
+    fn process_burn(&self, transaction: &Transaction) -> Vec<ArchiveCanvasIntent> {
+        let info = match &transaction.info {
+            Some(info) => info,
+            None => return vec![],
+        };
+        let canvas_struct_tag = self.get_canvas_struct_tag();
+        info.changes
+            .iter()
+            .filter_map(|change| match change.change.as_ref()? {
+                Change::DeleteResource(resource)
+                    if resource.r#type.as_ref() == Some(&canvas_struct_tag) =>
+                {
+                    Address::from_str(&resource.address).ok()
+                },
+                _ => None,
+            })
+            .map(|canvas_address| ArchiveCanvasIntent {
+                canvas_address,
+                txn_version: transaction.version,
+            })
+            .collect()
+    }
+}
+
+/// Whether getting a canvas from storage failed because it doesn't exist.
+fn is_canvas_not_found(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<PixelStorageError>(),
+        Some(PixelStorageError::CanvasNotFound { .. })
+    )
+}
+
+/// Get the time the transaction was committed, in seconds since the epoch.
+fn get_timestamp_secs(transaction: &Transaction) -> Result<u64> {
+    let timestamp = transaction.timestamp.as_ref().context("No timestamp")?;
+    u64::try_from(timestamp.seconds).context("Timestamp is negative")
 }
 
 fn entry_function_id_matches(
@@ -9,7 +9,7 @@ use aptos_processor_framework::{
     GrpcStreamSubscriberConfig, ProcessorTrait, StorageTrait, StreamSubscriberTrait,
 };
 use metadata_storage::PostgresMetadataStorage;
-use pixel_storage::MmapPixelStorage;
+use pixel_storage::{Palettes, PixelStorageTrait};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::task::JoinHandle;
@@ -29,7 +29,8 @@ pub struct RunConfig {
 pub async fn run(
     config: RunConfig,
     metadata_storage: Arc<PostgresMetadataStorage>,
-    pixels_storage: Arc<MmapPixelStorage>,
+    pixels_storage: Arc<dyn PixelStorageTrait>,
+    palettes: Arc<Palettes>,
 ) -> Result<Vec<JoinHandle<()>>> {
     // Build the canvas processor, which is what processes transactions and updates the
     // canvas storage and the DB.
@@ -38,6 +39,7 @@ pub async fn run(
             config.processor_config.clone(),
             pixels_storage.clone(),
             metadata_storage.clone(),
+            palettes,
         )
         .context("Failed to build processor")?,
     );
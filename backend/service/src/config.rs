@@ -1,21 +1,50 @@
+use crate::{
+    lifecycle::{ArchiveCanvasArgs, DeleteCanvasArgs},
+    reconcile::ReconcileArgs,
+    snapshot::{RestoreArgs, SnapshotArgs},
+    timelapse::TimelapseArgs,
+};
 use anyhow::Context as AnyhowContext;
 use api::ApiConfig;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use figment::{
     providers::{Env, Format, Yaml},
     Figment,
 };
-use flusher::{GcsFlusherConfig, LocalFlusherConfig};
+use flusher::{GcsFlusherConfig, LocalFlusherConfig, S3FlusherConfig};
 use metadata_storage::PostgresMetadataStorageConfig;
-use pixel_storage::MmapPixelStorageConfig;
+use pixel_storage::{
+    MemoryPixelStorage, MemoryPixelStorageConfig, MmapPixelStorage, MmapPixelStorageConfig,
+    ObjectPixelStorage, ObjectPixelStorageConfig, Palettes, PalettesConfig, PixelStorageTrait,
+};
 use processor::RunConfig;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
 #[derive(Debug, Parser)]
 pub struct Args {
     #[clap(short, long)]
     pub config_path: PathBuf,
+
+    /// If given, run this command and exit rather than running the service.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Render a timelapse of a canvas from its pixel history.
+    Timelapse(TimelapseArgs),
+    /// Write a snapshot of the canvases and processor progress to an archive.
+    Snapshot(SnapshotArgs),
+    /// Restore a snapshot written by `snapshot` into empty storage.
+    Restore(RestoreArgs),
+    /// Compare a canvas with a dump of its state on chain, optionally repairing it.
+    Reconcile(ReconcileArgs),
+    /// Archive a canvas so it can be read but never changed again.
+    ArchiveCanvas(ArchiveCanvasArgs),
+    /// Delete a canvas from pixel storage.
+    DeleteCanvas(DeleteCanvasArgs),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -27,7 +56,7 @@ pub enum Config {
     MetadataApiOnly(MetadataApiOnlyConfig),
 }
 
-impl TryFrom<Args> for Config {
+impl TryFrom<&Args> for Config {
     type Error = anyhow::Error;
 
     /// This function uses Figment to read the config. In short, it reads the config
@@ -50,22 +79,104 @@ impl TryFrom<Args> for Config {
     /// You could set that whole "path" with this env var:
     ///
     /// GRAFFIO___METADATA_STORAGE_CONFIG___CONNECTION_STRING=postgres://dport@localhost:5432/postgres
-    fn try_from(args: Args) -> Result<Self, Self::Error> {
+    fn try_from(args: &Args) -> Result<Self, Self::Error> {
         Figment::new()
-            .merge(Yaml::file(args.config_path))
+            .merge(Yaml::file(&args.config_path))
             .merge(Env::prefixed("GRAFFIO___").split("___"))
             .extract()
             .context("Failed to load config")
     }
 }
 
+impl Config {
+    /// The pixel storage and palettes config, if this mode has pixel storage.
+    pub fn pixel_storage_configs(&self) -> Option<(&PixelStorageConfig, &PalettesConfig)> {
+        match self {
+            Config::AllInOne(config) => {
+                Some((&config.pixel_storage_config, &config.palettes_config))
+            },
+            Config::ProcessorOnly(config) => {
+                Some((&config.pixel_storage_config, &config.palettes_config))
+            },
+            Config::MetadataApiOnly(_) => None,
+        }
+    }
+
+    /// The metadata storage config, if this mode runs the processor.
+    pub fn processor_metadata_storage_config(&self) -> Option<&PostgresMetadataStorageConfig> {
+        match self {
+            Config::AllInOne(config) => Some(&config.metadata_storage_config),
+            Config::ProcessorOnly(config) => Some(&config.metadata_storage_config),
+            Config::MetadataApiOnly(_) => None,
+        }
+    }
+}
+
+/// Which pixel storage to use, picked with `type`. Configs from before we had a
+/// choice have no `type`, for those we use mmap, see `deserialize_with_default_type`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PixelStorageConfig {
+    /// Store each canvas in a file on disk, accessed through an mmap.
+    Mmap(MmapPixelStorageConfig),
+    /// Keep everything in memory. Nothing survives a restart, so this is only
+    /// useful for testing and throwaway instances.
+    Memory(MemoryPixelStorageConfig),
+    /// Store each canvas as an image in an object store, so the processor doesn't
+    /// need a persistent disk. This doesn't keep history, so images of canvases in
+    /// the past and timelapses are unsupported.
+    Object(ObjectPixelStorageConfig),
+}
+
+impl PixelStorageConfig {
+    /// Deserialize the config, using mmap if `type` isn't set. Mmap was the only
+    /// pixel storage before we added `type`, so this keeps older configs working.
+    fn deserialize_with_default_type<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let mut value = serde_json::Value::deserialize(deserializer)?;
+        if let Some(fields) = value.as_object_mut() {
+            fields
+                .entry("type")
+                .or_insert_with(|| serde_json::Value::from("mmap"));
+        }
+        Self::deserialize(value).map_err(serde::de::Error::custom)
+    }
+
+    pub async fn build(
+        &self,
+        palettes: Arc<Palettes>,
+    ) -> anyhow::Result<Arc<dyn PixelStorageTrait>> {
+        Ok(match self {
+            PixelStorageConfig::Mmap(config) => Arc::new(
+                MmapPixelStorage::new(config.clone(), palettes)
+                    .context("Failed to initialize mmap pixel storage")?,
+            ),
+            PixelStorageConfig::Memory(config) => {
+                Arc::new(MemoryPixelStorage::new(config.clone(), palettes))
+            },
+            PixelStorageConfig::Object(config) => Arc::new(
+                ObjectPixelStorage::new(config.clone(), palettes)
+                    .await
+                    .context("Failed to initialize object pixel storage")?,
+            ),
+        })
+    }
+}
+
 /// Config for running the processor and API all in one. This is how we did it in
 /// banool/aptos-canvas and for the hackathon edition of Graffio.
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct AllInOneConfig {
     pub processor_config: RunConfig,
-    pub pixel_storage_config: MmapPixelStorageConfig,
+    #[serde(deserialize_with = "PixelStorageConfig::deserialize_with_default_type")]
+    pub pixel_storage_config: PixelStorageConfig,
+    // The palettes used by both the processor and pixel storage. If not set we use
+    // the palette from the canvas contract for every canvas.
+    #[serde(default)]
+    pub palettes_config: PalettesConfig,
     pub metadata_storage_config: PostgresMetadataStorageConfig,
     pub api_config: ApiConfig,
     // For testing purposes you may choose to also run a LocalFlusher.
@@ -77,18 +188,26 @@ pub struct AllInOneConfig {
 #[serde(deny_unknown_fields)]
 pub struct ProcessorOnlyConfig {
     pub processor_config: RunConfig,
-    pub pixel_storage_config: MmapPixelStorageConfig,
+    #[serde(deserialize_with = "PixelStorageConfig::deserialize_with_default_type")]
+    pub pixel_storage_config: PixelStorageConfig,
+    #[serde(default)]
+    pub palettes_config: PalettesConfig,
     pub metadata_storage_config: PostgresMetadataStorageConfig,
     // We take these params to know where to run the API but in main.rs we don't
     // actually hook up the pixel or metadata APIs, we only run the root API so
     // we can respond to health checks.
     pub api_config: ApiConfig,
-    pub gcs_flusher_config: GcsFlusherConfig,
+    // Where to publish canvas images. At least one of these must be set, if both are
+    // we publish to both. They're boxed to keep this config close in size to the
+    // others in `Config`.
+    pub gcs_flusher_config: Option<Box<GcsFlusherConfig>>,
+    pub s3_flusher_config: Option<Box<S3FlusherConfig>>,
 }
 
 /// Config for running just the metadata storage and metadata API. In this setup
 /// it is expected that serving pixel data is handled elsewhere. Generally this
-/// means that the processor is running somewhere else and using the GcsFlusher.
+/// means that the processor is running somewhere else and using the GcsFlusher or
+/// S3Flusher.
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct MetadataApiOnlyConfig {
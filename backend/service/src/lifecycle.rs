@@ -0,0 +1,60 @@
+// This module lets us archive or delete canvases by hand, e.g. to freeze a canvas
+// that is no longer in use or to clean up after a test. The processor archives
+// canvases that are burned on its own, so this is only needed for everything else.
+//
+// These change pixel storage directly, so the service must not be running against
+// the same storage while they run.
+
+use crate::config::{Config, PixelStorageConfig};
+use anyhow::{bail, Context as AnyhowContext, Result};
+use aptos_move_graphql_scalars::Address;
+use pixel_storage::{ArchiveCanvasIntent, Palettes, PixelStorageTrait};
+use std::sync::Arc;
+use tracing::info;
+
+/// Archive a canvas, so it can still be read but is never written to again.
+#[derive(Debug, clap::Args)]
+pub struct ArchiveCanvasArgs {
+    /// The address of the object containing the canvas.
+    #[clap(long)]
+    pub canvas_address: Address,
+}
+
+/// Delete a canvas from pixel storage, whether or not it is archived.
+#[derive(Debug, clap::Args)]
+pub struct DeleteCanvasArgs {
+    /// The address of the object containing the canvas.
+    #[clap(long)]
+    pub canvas_address: Address,
+}
+
+pub async fn run_archive_canvas(args: ArchiveCanvasArgs, config: &Config) -> Result<()> {
+    let pixels_storage = build_pixel_storage(config).await?;
+    pixels_storage
+        .archive_canvas(ArchiveCanvasIntent {
+            canvas_address: args.canvas_address,
+            txn_version: 0,
+        })
+        .await?;
+    info!("Archived canvas {}", args.canvas_address);
+    Ok(())
+}
+
+pub async fn run_delete_canvas(args: DeleteCanvasArgs, config: &Config) -> Result<()> {
+    let pixels_storage = build_pixel_storage(config).await?;
+    pixels_storage.delete_canvas(&args.canvas_address).await?;
+    info!("Deleted canvas {}", args.canvas_address);
+    Ok(())
+}
+
+async fn build_pixel_storage(config: &Config) -> Result<Arc<dyn PixelStorageTrait>> {
+    let (pixel_storage_config, palettes_config) = config
+        .pixel_storage_configs()
+        .context("This config has no pixel storage")?;
+    if let PixelStorageConfig::Memory(_) = pixel_storage_config {
+        bail!("In-memory pixel storage has no canvases to change");
+    }
+    let palettes =
+        Arc::new(Palettes::new(palettes_config.clone()).context("Invalid palettes config")?);
+    pixel_storage_config.build(palettes).await
+}
@@ -1,17 +1,27 @@
 mod config;
+mod lifecycle;
+mod reconcile;
+mod snapshot;
+mod timelapse;
 
 // This crate combines three different modes of operation for the sake of convenience:
 // 1. All in one: Run the processor and API in the same machine.
 // 2. Processor: Run the processor only.
 // 3. Metadata API: Run the metadata API only.
 
-use crate::config::{Args, Config};
-use anyhow::{Context as AnyhowContext, Result};
+use crate::{
+    config::{Args, Command, Config},
+    lifecycle::{run_archive_canvas, run_delete_canvas},
+    reconcile::run_reconcile,
+    snapshot::{run_restore, run_snapshot},
+    timelapse::run_timelapse,
+};
+use anyhow::{bail, Context as AnyhowContext, Result};
 use api::{build_full_route, start_api};
 use clap::Parser;
-use flusher::{FlusherTrait, GcsFlusher, LocalFlusher};
+use flusher::{FlusherTrait, GcsFlusher, LocalFlusher, S3Flusher};
 use metadata_storage::PostgresMetadataStorage;
-use pixel_storage::MmapPixelStorage;
+use pixel_storage::Palettes;
 use processor::run;
 use std::sync::Arc;
 use tokio::runtime::Builder;
@@ -31,7 +41,7 @@ fn main() -> Result<()> {
 
 async fn main_inner() -> Result<()> {
     let args = Args::parse();
-    let config = Config::try_from(args)?;
+    let config = Config::try_from(&args)?;
 
     let subscriber = FmtSubscriber::builder()
         // All spans of this level or more severe will be written to stdout.
@@ -41,12 +51,28 @@ async fn main_inner() -> Result<()> {
     tracing::subscriber::set_global_default(subscriber)
         .context("Setting default tracing subscriber failed")?;
 
+    if let Some(command) = args.command {
+        return match command {
+            Command::Timelapse(args) => run_timelapse(args, &config).await,
+            Command::Snapshot(args) => run_snapshot(args, &config).await,
+            Command::Restore(args) => run_restore(args, &config).await,
+            Command::Reconcile(args) => run_reconcile(args, &config).await,
+            Command::ArchiveCanvas(args) => run_archive_canvas(args, &config).await,
+            Command::DeleteCanvas(args) => run_delete_canvas(args, &config).await,
+        };
+    }
+
     let tasks = match config {
         Config::AllInOne(config) => {
+            // Build the palettes, which map the colors used in the contract to the
+            // colors we render.
+            let palettes = Arc::new(
+                Palettes::new(config.palettes_config.clone()).context("Invalid palettes config")?,
+            );
+
             // Build pixels storage, which is what lets us read and write to the
-            // representation of the canvas on disk.
-            let pixels_storage =
-                Arc::new(MmapPixelStorage::new(config.pixel_storage_config.clone()));
+            // representation of the canvas, generally on disk.
+            let pixels_storage = config.pixel_storage_config.build(palettes.clone()).await?;
 
             // Build the metadata storage, which is what lets us read and write to the
             // DB. This is generally necessary for all processors since they need
@@ -62,31 +88,39 @@ async fn main_inner() -> Result<()> {
                 config.processor_config,
                 metadata_storage.clone(),
                 pixels_storage.clone(),
+                palettes,
             )
             .await?;
 
-            // Run the API.
-            let route =
-                build_full_route(Some(pixels_storage.clone()), Some(metadata_storage.clone()))?;
-            let api_task = tokio::spawn(async move {
-                let result = start_api(config.api_config, route).await;
-                eprintln!("API finished unexpectedly: {:?}", result);
-            });
-
             // Run the local flusher if configured (helpful for testing).
+            let mut flusher_status_trackers = Vec::new();
             if let Some(config) = &config.local_flusher_config {
                 let local_flusher =
                     LocalFlusher::new(config.clone(), pixels_storage.clone()).await?;
+                flusher_status_trackers.push(local_flusher.status_tracker().clone());
                 tasks.push(local_flusher.run());
             }
 
+            // Run the API.
+            let route = build_full_route(
+                Some(pixels_storage.clone()),
+                Some(metadata_storage.clone()),
+                flusher_status_trackers,
+            )?;
+            let api_task = tokio::spawn(async move {
+                let result = start_api(config.api_config, route).await;
+                eprintln!("API finished unexpectedly: {:?}", result);
+            });
+
             // Return all the tasks.
             tasks.push(api_task);
             tasks
         },
         Config::ProcessorOnly(config) => {
-            let pixels_storage =
-                Arc::new(MmapPixelStorage::new(config.pixel_storage_config.clone()));
+            let palettes = Arc::new(
+                Palettes::new(config.palettes_config.clone()).context("Invalid palettes config")?,
+            );
+            let pixels_storage = config.pixel_storage_config.build(palettes.clone()).await?;
             let metadata_storage = Arc::new(
                 PostgresMetadataStorage::new(config.metadata_storage_config.clone())
                     .await
@@ -96,16 +130,31 @@ async fn main_inner() -> Result<()> {
                 config.processor_config,
                 metadata_storage,
                 pixels_storage.clone(),
+                palettes,
             )
             .await?;
 
-            // Run the GCS flusher.
-            let gcs_flusher =
-                GcsFlusher::new(config.gcs_flusher_config.clone(), pixels_storage.clone()).await?;
-            tasks.push(gcs_flusher.run());
+            // Run the flushers.
+            if config.gcs_flusher_config.is_none() && config.s3_flusher_config.is_none() {
+                bail!("At least one of gcs_flusher_config and s3_flusher_config must be set");
+            }
+            let mut flusher_status_trackers = Vec::new();
+            if let Some(gcs_flusher_config) = &config.gcs_flusher_config {
+                let gcs_flusher =
+                    GcsFlusher::new(*gcs_flusher_config.clone(), pixels_storage.clone()).await?;
+                flusher_status_trackers.push(gcs_flusher.status_tracker().clone());
+                tasks.push(gcs_flusher.run());
+            }
+            if let Some(s3_flusher_config) = &config.s3_flusher_config {
+                let s3_flusher =
+                    S3Flusher::new(*s3_flusher_config.clone(), pixels_storage.clone()).await?;
+                flusher_status_trackers.push(s3_flusher.status_tracker().clone());
+                tasks.push(s3_flusher.run());
+            }
 
-            // Run the API, but without the pixel or metadata APIs attached.
-            let route = build_full_route(None, None)?;
+            // Run the API, but without the pixel or metadata APIs attached. We still
+            // report the status of the flushers from the health endpoint.
+            let route = build_full_route(None, None, flusher_status_trackers)?;
             let api_task = tokio::spawn(async move {
                 let result = start_api(config.api_config, route).await;
                 eprintln!("API finished unexpectedly: {:?}", result);
@@ -120,7 +169,7 @@ async fn main_inner() -> Result<()> {
                     .await
                     .context("Failed to initialize Postgres storage")?,
             );
-            let route = build_full_route(None, Some(metadata_storage.clone()))?;
+            let route = build_full_route(None, Some(metadata_storage.clone()), vec![])?;
             let api_task = tokio::spawn(async move {
                 let result = start_api(config.api_config, route).await;
                 eprintln!("API finished unexpectedly: {:?}", result);
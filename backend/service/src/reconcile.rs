@@ -0,0 +1,186 @@
+// This module checks a canvas in local pixel storage against the `pixels`
+// SmartTable on chain, for when we suspect the two have drifted apart.
+//
+// The on-chain state comes from a JSON file put together from node REST API
+// responses, so we don't need a node connection here:
+//
+// {
+//   "ledger_version": "123456",
+//   "canvas": <GET /accounts/{canvas_address}/resource/{addr}::canvas_token::Canvas>,
+//   "pixel_buckets": [<POST /tables/{pixels.buckets.inner.handle}/item>, ...]
+// }
+//
+// `ledger_version` is the version the resource and table items were read at, e.g.
+// by passing `?ledger_version=` to each request. Each item in `pixel_buckets` is a
+// bucket of the SmartTable, which is a list of entries with a u32 key (the pixel
+// index) and u8 value (the color). Pixels that aren't in any bucket have the
+// default color of the canvas.
+
+use crate::config::{Config, PixelStorageConfig};
+use anyhow::{bail, Context as AnyhowContext, Result};
+use aptos_move_graphql_scalars::Address;
+use move_types::{Canvas, Entry};
+use pixel_storage::{MmapPixelStorage, Palettes, PixelRepair};
+use serde::Deserialize;
+use std::{path::PathBuf, sync::Arc};
+use tracing::{info, warn};
+
+/// Compare a canvas in local pixel storage with a dump of its state on chain.
+#[derive(Debug, clap::Args)]
+pub struct ReconcileArgs {
+    /// The address of the object containing the canvas.
+    #[clap(long)]
+    pub canvas_address: Address,
+
+    /// The dump of the canvas' on-chain state, see `reconcile.rs` for the format.
+    #[clap(long)]
+    pub dump_path: PathBuf,
+
+    /// Overwrite local pixels that don't match the chain. The processor should not
+    /// be running while this is set.
+    #[clap(long)]
+    pub repair: bool,
+
+    /// Only log this many mismatched pixels, the rest are just counted.
+    #[clap(long, default_value_t = 100)]
+    pub max_logged_mismatches: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct CanvasDump {
+    ledger_version: String,
+    canvas: ResourceResponse,
+    pixel_buckets: Vec<Vec<Entry>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResourceResponse {
+    data: Canvas,
+}
+
+pub async fn run_reconcile(args: ReconcileArgs, config: &Config) -> Result<()> {
+    let (pixel_storage_config, palettes_config) = config
+        .pixel_storage_configs()
+        .context("This config has no pixel storage to reconcile")?;
+    let mut pixel_storage_config = match pixel_storage_config {
+        PixelStorageConfig::Mmap(config) => config.clone(),
+        PixelStorageConfig::Memory(_) => {
+            bail!("In-memory pixel storage has nothing to reconcile")
+        },
+        PixelStorageConfig::Object(_) => {
+            bail!("Reconciling is only supported for mmap pixel storage")
+        },
+    };
+    // The service might be running against the same storage directory, so we leave
+    // upgrading legacy files to it.
+    pixel_storage_config.upgrade_legacy_files = false;
+    let palettes =
+        Arc::new(Palettes::new(palettes_config.clone()).context("Invalid palettes config")?);
+    let pixels_storage = MmapPixelStorage::new(pixel_storage_config, palettes)
+        .context("Failed to initialize mmap pixel storage")?;
+
+    let data = std::fs::read(&args.dump_path)
+        .with_context(|| format!("Failed to read {}", args.dump_path.display()))?;
+    let dump: CanvasDump = serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse {}", args.dump_path.display()))?;
+    let ledger_version: u64 = dump
+        .ledger_version
+        .parse()
+        .context("Failed to parse ledger_version")?;
+    let on_chain = on_chain_pixels(&dump)?;
+
+    let local = pixels_storage
+        .read_canvas_pixels(&args.canvas_address)
+        .await?;
+    let width = dump.canvas.data.config.width as u32;
+    let height = dump.canvas.data.config.height as u32;
+    if (local.info.width, local.info.height) != (width, height) {
+        bail!(
+            "Canvas {} is {}x{} locally but {}x{} on chain",
+            args.canvas_address,
+            local.info.width,
+            local.info.height,
+            width,
+            height
+        );
+    }
+    // If the local canvas has txns the dump doesn't, some mismatches are just the
+    // dump being out of date. If the processor hasn't caught up to the dump yet the
+    // reverse is true, but we can't tell that from the canvas alone.
+    let local_is_ahead = local.last_applied_version > ledger_version;
+    if local_is_ahead {
+        warn!(
+            "Canvas {} has txns up to version {} applied but the dump is from version {}, \
+            mismatches might be from txns after the dump",
+            args.canvas_address, local.last_applied_version, ledger_version
+        );
+    }
+
+    let mut repairs = Vec::new();
+    for (index, (local_color, on_chain_color)) in
+        local.pixels.iter().zip(on_chain.iter()).enumerate()
+    {
+        if local_color == on_chain_color {
+            continue;
+        }
+        if repairs.len() < args.max_logged_mismatches {
+            warn!(
+                "Pixel {} (x: {}, y: {}) is color {} locally but {} on chain",
+                index,
+                index as u32 % width,
+                index as u32 / width,
+                local_color,
+                on_chain_color
+            );
+        }
+        repairs.push(PixelRepair {
+            index: index as u32,
+            color: *on_chain_color,
+        });
+    }
+
+    info!(
+        "Canvas {} has {} of {} pixels that don't match the chain at version {}",
+        args.canvas_address,
+        repairs.len(),
+        on_chain.len(),
+        ledger_version
+    );
+
+    if args.repair && !repairs.is_empty() {
+        if local_is_ahead {
+            bail!(
+                "Refusing to repair canvas {} with a dump older than its last applied txn",
+                args.canvas_address
+            );
+        }
+        pixels_storage
+            .repair_pixels(&args.canvas_address, &repairs)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Build the color of every pixel on chain from the dump.
+fn on_chain_pixels(dump: &CanvasDump) -> Result<Vec<u8>> {
+    let config = &dump.canvas.data.config;
+    let num_pixels = config.width as usize * config.height as usize;
+    let mut pixels = vec![config.default_color; num_pixels];
+    for entry in dump.pixel_buckets.iter().flatten() {
+        let index = entry
+            .key
+            .as_u64()
+            .with_context(|| format!("Unexpected pixel index {}", entry.key))?;
+        let color = entry
+            .value
+            .as_u64()
+            .and_then(|color| u8::try_from(color).ok())
+            .with_context(|| format!("Unexpected color {} for pixel {}", entry.value, index))?;
+        let pixel = pixels
+            .get_mut(index as usize)
+            .with_context(|| format!("Pixel index {} on chain is out of bounds", index))?;
+        *pixel = color;
+    }
+    Ok(pixels)
+}
@@ -0,0 +1,318 @@
+// This module lets us take a snapshot of everything the processor has built up, so
+// we can stand up a new instance without reprocessing the chain from the start.
+//
+// A snapshot is a gzipped tarball containing `snapshot.json`, which describes where
+// the processor was up to, and a canvas file (plus history file, if there is one)
+// for each canvas under `canvases/`. Archived canvases go under `canvases/archive/`
+// and are restored into the archive directory.
+//
+// Pixel attribution is not part of the snapshot. After a restore, attribution only
+// covers pixels drawn after the snapshot was taken.
+
+use crate::config::{Config, PixelStorageConfig};
+use anyhow::{bail, Context as AnyhowContext, Result};
+use aptos_move_graphql_scalars::Address;
+use aptos_processor_framework::StorageTrait;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use metadata_storage::PostgresMetadataStorage;
+use pixel_storage::{CanvasSnapshot, MmapPixelStorage, MmapPixelStorageConfig, Palettes};
+use processor::CANVAS_PROCESSOR_NAME;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use tracing::info;
+
+const MANIFEST_PATH: &str = "snapshot.json";
+const CANVASES_DIRECTORY: &str = "canvases";
+const ARCHIVE_DIRECTORY: &str = "archive";
+const CANVAS_EXTENSION: &str = "canvas";
+const HISTORY_EXTENSION: &str = "history";
+
+/// Write a snapshot of the canvas storage directory and the processor's progress to a
+/// single archive. This is safe to run while the processor is running.
+#[derive(Debug, clap::Args)]
+pub struct SnapshotArgs {
+    /// Where to write the snapshot, e.g. snapshot.tar.gz.
+    #[clap(long)]
+    pub output_path: PathBuf,
+}
+
+/// Restore a snapshot into an empty storage directory and a DB the processor hasn't
+/// run against yet. The service must not be running while this runs.
+#[derive(Debug, clap::Args)]
+pub struct RestoreArgs {
+    /// The snapshot to restore, as written by the snapshot command.
+    #[clap(long)]
+    pub snapshot_path: PathBuf,
+}
+
+/// Describes where the processor was up to when the snapshot was taken.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct SnapshotManifest {
+    processor_name: String,
+    chain_id: u8,
+    last_processed_version: u64,
+}
+
+pub async fn run_snapshot(args: SnapshotArgs, config: &Config) -> Result<()> {
+    let (pixel_storage_config, palettes) = get_mmap_config(config)?;
+    let metadata_storage = build_metadata_storage(config).await?;
+
+    // We read where the processor is up to before reading any canvases. Every canvas
+    // is therefore at least as far along as this version, and the processor skips
+    // anything a canvas has already applied when it replays from here after a
+    // restore.
+    let chain_id = metadata_storage
+        .read_chain_id()
+        .await?
+        .context("The DB has no chain ID, has the processor run yet?")?;
+    let last_processed_version = metadata_storage
+        .read_last_processed_version(CANVAS_PROCESSOR_NAME)
+        .await?
+        .context("The DB has no last processed version, has the processor run yet?")?;
+    let manifest = SnapshotManifest {
+        processor_name: CANVAS_PROCESSOR_NAME.to_string(),
+        chain_id,
+        last_processed_version,
+    };
+
+    let mut pixel_storage_config = pixel_storage_config;
+    // The service might be running against the same storage directory, so we leave
+    // upgrading legacy files to it.
+    pixel_storage_config.upgrade_legacy_files = false;
+    let pixels_storage = MmapPixelStorage::new(pixel_storage_config, palettes)
+        .context("Failed to initialize mmap pixel storage")?;
+
+    // Write to a temporary file first so we never leave a partial snapshot behind.
+    let tmp_path = args.output_path.with_extension("tmp");
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    append_file(
+        &mut builder,
+        MANIFEST_PATH,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    let mut num_canvases = 0;
+    pixels_storage.snapshot_canvases(|snapshot| {
+        let canvas_address = snapshot.canvas_address;
+        append_file(
+            &mut builder,
+            &canvas_path(&canvas_address, snapshot.archived, CANVAS_EXTENSION),
+            &snapshot.canvas_file,
+        )?;
+        if let Some(history_file) = &snapshot.history_file {
+            append_file(
+                &mut builder,
+                &canvas_path(&canvas_address, snapshot.archived, HISTORY_EXTENSION),
+                history_file,
+            )?;
+        }
+        num_canvases += 1;
+        Ok(())
+    })?;
+    builder
+        .into_inner()
+        .context("Failed to finish snapshot archive")?
+        .finish()
+        .context("Failed to finish compressing snapshot")?;
+    std::fs::rename(&tmp_path, &args.output_path)
+        .with_context(|| format!("Failed to move snapshot to {}", args.output_path.display()))?;
+
+    info!(
+        "Wrote snapshot of {} canvases at version {} to {}",
+        num_canvases,
+        last_processed_version,
+        args.output_path.display()
+    );
+
+    Ok(())
+}
+
+pub async fn run_restore(args: RestoreArgs, config: &Config) -> Result<()> {
+    let (pixel_storage_config, palettes) = get_mmap_config(config)?;
+
+    // Read the whole snapshot before touching anything, so a bad snapshot leaves
+    // everything as it was.
+    let (manifest, canvases) = read_snapshot(&args.snapshot_path)?;
+    if manifest.processor_name != CANVAS_PROCESSOR_NAME {
+        bail!(
+            "Snapshot is for processor {}, expected {}",
+            manifest.processor_name,
+            CANVAS_PROCESSOR_NAME
+        );
+    }
+
+    let storage_directory = &pixel_storage_config.storage_directory;
+    std::fs::create_dir_all(storage_directory)
+        .with_context(|| format!("Failed to create {}", storage_directory.display()))?;
+    if std::fs::read_dir(storage_directory)?.next().is_some() {
+        bail!(
+            "Storage directory {} is not empty, refusing to restore into it",
+            storage_directory.display()
+        );
+    }
+
+    let metadata_storage = build_metadata_storage(config).await?;
+    if let Some(chain_id) = metadata_storage.read_chain_id().await? {
+        bail!(
+            "The DB already has chain ID {}, refusing to restore into it",
+            chain_id
+        );
+    }
+    if let Some(version) = metadata_storage
+        .read_last_processed_version(CANVAS_PROCESSOR_NAME)
+        .await?
+    {
+        bail!(
+            "The DB already has last processed version {}, refusing to restore into it",
+            version
+        );
+    }
+
+    // Write the canvases before the DB. If we fail partway through, the DB still
+    // says the processor hasn't run, so the processor won't start from the snapshot
+    // version with half the canvases missing.
+    let pixels_storage = MmapPixelStorage::new(pixel_storage_config, palettes)
+        .context("Failed to initialize mmap pixel storage")?;
+    for canvas in canvases.values() {
+        pixels_storage.restore_canvas(canvas)?;
+    }
+    metadata_storage.write_chain_id(manifest.chain_id).await?;
+    metadata_storage
+        .write_last_processed_version(CANVAS_PROCESSOR_NAME, manifest.last_processed_version)
+        .await?;
+
+    info!(
+        "Restored snapshot of {} canvases at version {} from {}",
+        canvases.len(),
+        manifest.last_processed_version,
+        args.snapshot_path.display()
+    );
+
+    Ok(())
+}
+
+fn get_mmap_config(config: &Config) -> Result<(MmapPixelStorageConfig, Arc<Palettes>)> {
+    let (pixel_storage_config, palettes_config) = config
+        .pixel_storage_configs()
+        .context("This config has no pixel storage to snapshot")?;
+    let pixel_storage_config = match pixel_storage_config {
+        PixelStorageConfig::Mmap(config) => config.clone(),
+        PixelStorageConfig::Memory(_) | PixelStorageConfig::Object(_) => {
+            bail!("Snapshots are only supported for mmap pixel storage")
+        },
+    };
+    let palettes =
+        Arc::new(Palettes::new(palettes_config.clone()).context("Invalid palettes config")?);
+    Ok((pixel_storage_config, palettes))
+}
+
+async fn build_metadata_storage(config: &Config) -> Result<PostgresMetadataStorage> {
+    let metadata_storage_config = config
+        .processor_metadata_storage_config()
+        .context("This config has no processor to snapshot")?;
+    PostgresMetadataStorage::new(metadata_storage_config.clone())
+        .await
+        .context("Failed to initialize Postgres storage")
+}
+
+fn canvas_path(canvas_address: &Address, archived: bool, extension: &str) -> String {
+    if archived {
+        format!(
+            "{}/{}/{}.{}",
+            CANVASES_DIRECTORY, ARCHIVE_DIRECTORY, canvas_address, extension
+        )
+    } else {
+        format!("{}/{}.{}", CANVASES_DIRECTORY, canvas_address, extension)
+    }
+}
+
+fn append_file<W: std::io::Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, path, data)
+        .with_context(|| format!("Failed to add {} to snapshot", path))
+}
+
+/// Read the manifest and every canvas from a snapshot.
+fn read_snapshot(path: &Path) -> Result<(SnapshotManifest, HashMap<Address, CanvasSnapshot>)> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+    let mut manifest = None;
+    // Canvas files are keyed by address and whether the canvas is archived.
+    let mut canvas_files = HashMap::new();
+    let mut history_files = HashMap::new();
+    for entry in archive.entries().context("Failed to read snapshot")? {
+        let mut entry = entry.context("Failed to read snapshot")?;
+        let entry_path = entry.path()?.to_string_lossy().to_string();
+        let mut data = Vec::new();
+        entry
+            .read_to_end(&mut data)
+            .with_context(|| format!("Failed to read {} from snapshot", entry_path))?;
+
+        if entry_path == MANIFEST_PATH {
+            manifest = Some(serde_json::from_slice::<SnapshotManifest>(&data)?);
+            continue;
+        }
+        let name = entry_path
+            .strip_prefix(&format!("{}/", CANVASES_DIRECTORY))
+            .with_context(|| format!("Unexpected file {} in snapshot", entry_path))?;
+        let (name, archived) = match name.strip_prefix(&format!("{}/", ARCHIVE_DIRECTORY)) {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let (canvas_address, extension) = name
+            .rsplit_once('.')
+            .with_context(|| format!("Unexpected file {} in snapshot", entry_path))?;
+        let canvas_address = Address::from_str(canvas_address)
+            .with_context(|| format!("Unexpected file {} in snapshot", entry_path))?;
+        match extension {
+            CANVAS_EXTENSION => canvas_files.insert((canvas_address, archived), data),
+            HISTORY_EXTENSION => history_files.insert((canvas_address, archived), data),
+            _ => bail!("Unexpected file {} in snapshot", entry_path),
+        };
+    }
+
+    let manifest = manifest.context("Snapshot has no manifest")?;
+    if let Some((canvas_address, _)) = history_files
+        .keys()
+        .find(|key| !canvas_files.contains_key(*key))
+    {
+        bail!(
+            "Snapshot has a history file but no canvas file for {}",
+            canvas_address
+        );
+    }
+    let mut canvases = HashMap::new();
+    for ((canvas_address, archived), canvas_file) in canvas_files {
+        let snapshot = CanvasSnapshot {
+            canvas_address,
+            canvas_file,
+            history_file: history_files.remove(&(canvas_address, archived)),
+            archived,
+        };
+        if canvases.insert(canvas_address, snapshot).is_some() {
+            bail!(
+                "Snapshot has canvas {} both archived and not archived",
+                canvas_address
+            );
+        }
+    }
+    Ok((manifest, canvases))
+}
@@ -0,0 +1,129 @@
+use crate::config::{Config, PixelStorageConfig};
+use anyhow::{bail, Context as AnyhowContext, Result};
+use aptos_move_graphql_scalars::Address;
+use clap::ArgGroup;
+use pixel_storage::{
+    FrameInterval, MmapPixelStorage, Palettes, PixelStorageTrait, TimelapseOptions,
+};
+use std::{path::PathBuf, sync::Arc};
+use tracing::info;
+
+/// Render a timelapse of a canvas. This only works for canvases created while
+/// `record_history` was set in the pixel storage config.
+#[derive(Debug, clap::Args)]
+#[clap(group(
+    ArgGroup::new("interval")
+        .required(true)
+        .args(["interval_versions", "interval_secs"]),
+))]
+#[clap(group(
+    ArgGroup::new("output")
+        .required(true)
+        .multiple(true)
+        .args(["animation_path", "frames_directory"]),
+))]
+pub struct TimelapseArgs {
+    /// The address of the object containing the canvas.
+    #[clap(long)]
+    pub canvas_address: Address,
+
+    /// Take a frame every this many txn versions.
+    #[clap(long)]
+    pub interval_versions: Option<u64>,
+
+    /// Take a frame every this many seconds.
+    #[clap(long)]
+    pub interval_secs: Option<u64>,
+
+    /// Each pixel of the canvas becomes a square this many pixels wide.
+    #[clap(long, default_value_t = 1)]
+    pub scale: u32,
+
+    /// If the interval would give more frames than this, it is widened so the
+    /// timelapse still covers the whole history of the canvas.
+    #[clap(long, default_value_t = 600)]
+    pub max_frames: usize,
+
+    /// How long each frame of the animation is shown for.
+    #[clap(long, default_value_t = 100)]
+    pub frame_delay_ms: u16,
+
+    /// Where to write the animation. Use a .png extension for an animated PNG or
+    /// .gif for a GIF.
+    #[clap(long)]
+    pub animation_path: Option<PathBuf>,
+
+    /// A directory to write each frame to as a numbered png.
+    #[clap(long)]
+    pub frames_directory: Option<PathBuf>,
+}
+
+pub async fn run_timelapse(args: TimelapseArgs, config: &Config) -> Result<()> {
+    let (pixel_storage_config, palettes_config) = config
+        .pixel_storage_configs()
+        .context("This config has no pixel storage to render a timelapse from")?;
+
+    let interval = match (args.interval_versions, args.interval_secs) {
+        (Some(versions), _) => FrameInterval::Versions(versions),
+        (None, Some(secs)) => FrameInterval::Seconds(secs),
+        (None, None) => bail!("Either --interval-versions or --interval-secs must be set"),
+    };
+    let animation_extension = args
+        .animation_path
+        .as_ref()
+        .map(|path| path.extension().and_then(|extension| extension.to_str()));
+    if let Some(extension) = animation_extension {
+        if !matches!(extension, Some("png") | Some("gif")) {
+            bail!("--animation-path must end in .png or .gif");
+        }
+    }
+
+    let palettes =
+        Arc::new(Palettes::new(palettes_config.clone()).context("Invalid palettes config")?);
+
+    let mut pixel_storage_config = match pixel_storage_config {
+        PixelStorageConfig::Mmap(config) => config.clone(),
+        PixelStorageConfig::Memory(_) => {
+            bail!("In-memory pixel storage has no history to render a timelapse from")
+        },
+        PixelStorageConfig::Object(_) => {
+            bail!("Object pixel storage has no history to render a timelapse from")
+        },
+    };
+    // The service might be running against the same storage directory, so we leave
+    // upgrading legacy files to it.
+    pixel_storage_config.upgrade_legacy_files = false;
+    let pixels_storage = MmapPixelStorage::new(pixel_storage_config, palettes)
+        .context("Failed to initialize mmap pixel storage")?;
+
+    let timelapse = pixels_storage
+        .get_canvas_timelapse(&args.canvas_address, &TimelapseOptions {
+            interval,
+            scale: args.scale,
+            max_frames: args.max_frames,
+        })
+        .await?;
+    info!(
+        "Rendered {} frames of canvas {}",
+        timelapse.frames.len(),
+        args.canvas_address
+    );
+
+    if let Some(path) = &args.animation_path {
+        let data = if animation_extension == Some(Some("gif")) {
+            timelapse.to_gif(args.frame_delay_ms)?
+        } else {
+            timelapse.to_apng(args.frame_delay_ms)?
+        };
+        std::fs::write(path, data)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        info!("Wrote animation to {}", path.display());
+    }
+
+    if let Some(directory) = &args.frames_directory {
+        timelapse.write_frames(directory)?;
+        info!("Wrote frames to {}", directory.display());
+    }
+
+    Ok(())
+}